@@ -1,83 +1,358 @@
-use anyhow::Result;
-use clap::Parser;
-use std::{fs, path::PathBuf, process};
-use wasmtime::{Config, Engine, Linker, Store};
-use wasmtime_wasi::{
-    p1::WasiP1Ctx, p2::pipe::MemoryInputPipe, DirPerms, FilePerms, WasiCtxBuilder,
+mod run;
+
+use anyhow::{bail, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use ruvy::{CompileError, Compiler, Limits, Metadata};
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::PathBuf,
+    process,
 };
-use wasmtime_wizer::Wizer;
 
 #[derive(Debug, Parser)]
-#[clap(name = "ruvy_cli", about = "Compile ruby code into a Wasm module.")]
+#[clap(
+    name = "ruvy_cli",
+    about = "Compile ruby code into a Wasm module.",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Opt {
-    /// Path of the Ruby input file.
-    input: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Path of a directory containing Ruby files to preload to be used by the input file.
-    #[arg(long)]
-    preload: Option<PathBuf>,
+    #[command(flatten)]
+    build: BuildArgs,
 
     #[arg(short, default_value = "index.wasm")]
     /// Desired path of the WebAssembly output file.
     output: PathBuf,
 }
 
+/// Options of the module to build.
+#[derive(Debug, Args)]
+struct BuildArgs {
+    /// Path of the Ruby input file.
+    #[arg(required = true)]
+    input: Option<PathBuf>,
+
+    /// Path of a Ruby file, or of a directory containing Ruby files, to preload to be used by
+    /// the input file.
+    ///
+    /// Can be passed multiple times. Directories are searched recursively for `.rb` files,
+    /// which are loaded in path order unless the directory contains a `ruvy-preload.txt`
+    /// manifest listing the files to load.
+    #[arg(long, value_name = "PATH")]
+    preload: Vec<PathBuf>,
+
+    /// Path of a directory to add to `$LOAD_PATH` when resolving `require` calls.
+    ///
+    /// Can be passed multiple times. Files are required when the module is built,
+    /// so the directories are not needed at runtime. Only `require` and
+    /// `require_relative` calls taking a string literal are resolved, wherever they
+    /// are in the input file, preloaded files and the files they load from these
+    /// directories.
+    #[arg(short = 'I', long = "load-path", value_name = "DIR")]
+    load_paths: Vec<PathBuf>,
+
+    /// Comma-separated names of Ruby standard libraries to load, e.g. `json,set`.
+    ///
+    /// The files the libraries require are loaded from the standard library
+    /// of ruby.wasm when the module is built.
+    #[arg(long, value_name = "LIBRARIES", value_delimiter = ',')]
+    stdlib: Vec<String>,
+
+    /// Path of a `Gemfile.lock` whose gems are loaded when the module is built.
+    ///
+    /// Only pure-Ruby gems from a gem server are supported.
+    #[arg(long, value_name = "LOCKFILE")]
+    gemfile: Option<PathBuf>,
+
+    /// Path of the directory containing the `.gem` files of the gems locked in
+    /// the `--gemfile`. Defaults to `vendor/cache` next to the lockfile.
+    #[arg(long, value_name = "DIR", requires = "gemfile")]
+    gem_cache: Option<PathBuf>,
+
+    /// Emit a small module that imports the Ruby engine instead of embedding it.
+    ///
+    /// The engine module can be created with the `emit-engine` subcommand.
+    #[arg(long)]
+    dynamic: bool,
+
+    /// Name of a top-level Ruby method to export as a Wasm function of the same name.
+    ///
+    /// Can be passed multiple times. When used, the module does not export `_start`.
+    #[arg(long = "export", value_name = "METHOD")]
+    exports: Vec<String>,
+
+    /// Shrink the module by stripping debug information, code that can only run
+    /// while the module is built and redundant data, and report the sizes before
+    /// and after on stderr.
+    #[arg(long)]
+    optimize: bool,
+
+    /// Measure the Ruby objects retained by the module's snapshot, and report them
+    /// by class and by the library or file that loaded them on stderr.
+    #[arg(long)]
+    heap_report: bool,
+
+    /// Make building the same inputs produce the same module, wherever the build runs.
+    ///
+    /// While the module is built, Ruby gets random numbers seeded from the input
+    /// file, a clock stopped at `SOURCE_DATE_EPOCH` or the Unix epoch, and sorted
+    /// directory listings.
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Build the module even if an identical one is in the build cache, and don't
+    /// store it there.
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Directory of the build cache, which stores modules by the hashes of what
+    /// they're built from.
+    ///
+    /// Defaults to `RUVY_CACHE_DIR`, or the `ruvy` directory of `XDG_CACHE_HOME` or
+    /// `~/.cache`.
+    #[arg(long, value_name = "DIR", conflicts_with = "no_cache")]
+    cache_dir: Option<PathBuf>,
+
+    /// Platform to build the module for.
+    #[arg(long, value_enum, default_value_t = Target::Wasi)]
+    target: Target,
+
+    /// Format of the data `Ruvy.input` reads from stdin and `Ruvy.output` writes to stdout.
+    #[arg(long, value_enum, default_value_t = IoFormat::Raw)]
+    io_format: IoFormat,
+
+    /// Maximum size in bytes of modules built for the `shopify-function` target.
+    ///
+    /// Defaults to the limit Shopify Functions enforce, 256 KiB.
+    #[arg(long, value_name = "BYTES", help_heading = SHOPIFY_FUNCTION_HEADING)]
+    max_size: Option<u64>,
+
+    /// Maximum initial linear memory in bytes of modules built for the
+    /// `shopify-function` target.
+    ///
+    /// Defaults to the limit Shopify Functions enforce, 10,000 KiB.
+    #[arg(long, value_name = "BYTES", help_heading = SHOPIFY_FUNCTION_HEADING)]
+    max_memory: Option<u64>,
+
+    /// Module that modules built for the `shopify-function` target can import from.
+    ///
+    /// Can be passed multiple times. Defaults to `wasi_snapshot_preview1` and
+    /// `ruvy_engine_v1`, the engine modules built with `--dynamic` import.
+    #[arg(
+        long = "allow-import-module",
+        value_name = "MODULE",
+        help_heading = SHOPIFY_FUNCTION_HEADING
+    )]
+    allowed_import_modules: Vec<String>,
+}
+
+const SHOPIFY_FUNCTION_HEADING: &str = "Shopify Function target";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Target {
+    /// A WASI command running the script from `_start`.
+    Wasi,
+    /// A Shopify Function whose `run` export calls the script's `run` method
+    /// with the input parsed from JSON on stdin, and writes the method's
+    /// result as JSON on stdout.
+    ShopifyFunction,
+}
+
+impl From<Target> for ruvy::Target {
+    fn from(target: Target) -> Self {
+        match target {
+            Target::Wasi => ruvy::Target::Wasi,
+            Target::ShopifyFunction => ruvy::Target::ShopifyFunction,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum IoFormat {
+    /// Binary strings, read and written as is.
+    Raw,
+    /// JSON documents, parsed and generated like `JSON.parse` and `JSON.generate`.
+    Json,
+    /// MessagePack objects, unpacked and packed like `MessagePack.unpack` and
+    /// `MessagePack.pack`.
+    Msgpack,
+}
+
+impl From<IoFormat> for ruvy::IoFormat {
+    fn from(io_format: IoFormat) -> Self {
+        match io_format {
+            IoFormat::Raw => ruvy::IoFormat::Raw,
+            IoFormat::Json => ruvy::IoFormat::Json,
+            IoFormat::Msgpack => ruvy::IoFormat::Msgpack,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Emit the Ruby engine module imported by modules compiled with `--dynamic`.
+    EmitEngine {
+        #[arg(short, default_value = "engine.wasm")]
+        /// Desired path of the WebAssembly output file.
+        output: PathBuf,
+    },
+    /// Run a function of a compiled module, `_start` by default, and exit with its exit code.
+    Run(run::RunArgs),
+    /// Print the imports, exports, memories, data segments, custom sections and
+    /// metadata of a module.
+    Inspect {
+        /// Path of the Wasm module.
+        wasm: PathBuf,
+
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Build a module twice in `--reproducible` mode, and fail if the builds differ.
+    VerifyReproducible(BuildArgs),
+    /// Print the versions, sources and options a module was built with as JSON.
+    Metadata {
+        /// Path of the Wasm module.
+        wasm: PathBuf,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let opt = Opt::parse();
-    let ruby_code = match fs::read_to_string(&opt.input) {
-        Ok(code) => code,
-        Err(err) => {
-            eprintln!("Error reading Ruby file {}: {}", opt.input.display(), err);
-            process::exit(1);
-        }
-    };
 
-    let ruby_engine = include_bytes!(concat!(env!("OUT_DIR"), "/engine.wasm"));
-    let user_wasm = wizen(ruby_engine, &ruby_code, opt.preload).await?;
+    match opt.command {
+        Some(Command::EmitEngine { output }) => {
+            fs::write(output, ruvy::emit_engine(ruvy::ENGINE).await?)?;
+            return Ok(());
+        }
+        Some(Command::Run(args)) => process::exit(run::run(&args).await?),
+        Some(Command::Inspect { wasm, json }) => {
+            let inspection = ruvy::inspect(&fs::read(wasm)?)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&inspection)?);
+            } else {
+                println!("{inspection}");
+            }
+            return Ok(());
+        }
+        Some(Command::Metadata { wasm }) => {
+            let Some(metadata) = Metadata::read(&fs::read(&wasm)?)? else {
+                bail!("{} was not built by ruvy", wasm.display());
+            };
+            println!("{}", serde_json::to_string_pretty(&metadata)?);
+            return Ok(());
+        }
+        Some(Command::VerifyReproducible(args)) => {
+            let verification = compiler(args)?.verify_reproducible().await?;
+            match verification.difference {
+                None => println!("The builds are identical: {}", verification.sha256),
+                Some(difference) => bail!("{difference}"),
+            }
+            return Ok(());
+        }
+        None => {}
+    }
 
-    fs::write(opt.output, user_wasm)?;
+    let output = match compiler(opt.build)?.compile().await {
+        Ok(output) => output,
+        // Ruby errors are printed as they would be by `ruby`, along with
+        // what was printed before them.
+        Err(err) => match err.downcast::<CompileError>() {
+            Ok(err) => {
+                io::stdout().write_all(&err.stdout)?;
+                for diagnostic in &err.diagnostics {
+                    eprintln!("{diagnostic}");
+                }
+                process::exit(err.exit_code);
+            }
+            Err(err) => return Err(err),
+        },
+    };
+    io::stdout().write_all(&output.stdout)?;
+    for diagnostic in &output.diagnostics {
+        eprintln!("{diagnostic}");
+    }
+    if let Some(size_report) = output.size_report {
+        eprintln!("{size_report}");
+    }
+    if let Some(heap_report) = output.heap_report {
+        eprintln!("{heap_report}");
+    }
+    fs::write(opt.output, output.wasm)?;
     Ok(())
 }
 
-async fn wizen(
-    ruby_engine: &[u8],
-    ruby_code: &str,
-    preload_path: Option<PathBuf>,
-) -> Result<Vec<u8>> {
-    let mut cfg = Config::new();
-    cfg.async_support(true);
-    let engine = Engine::new(&cfg)?;
-    let mut store = Store::new(&engine, wasi(ruby_code, preload_path)?);
-    let user_wasm = Wizer::new()
-        .run(&mut store, ruby_engine, async |store, module| {
-            let engine = store.engine();
-            let mut linker = Linker::new(engine);
-            wasmtime_wasi::p1::add_to_linker_async(&mut linker, |cx| cx)?;
-            let instance = linker.instantiate_async(store, module).await?;
-            Ok(instance)
-        })
-        .await?;
-    Ok(user_wasm)
+/// Configures a compiler from the command line options.
+fn compiler(args: BuildArgs) -> Result<Compiler> {
+    let mut compiler = Compiler::from_file(args.input.unwrap())?;
+    for path in args.preload {
+        compiler.preload(path);
+    }
+    for dir in args.load_paths {
+        compiler.load_path(dir);
+    }
+    for library in args.stdlib {
+        compiler.stdlib(library);
+    }
+    if let Some(gemfile) = args.gemfile {
+        compiler.gemfile(gemfile);
+    }
+    if let Some(gem_cache) = args.gem_cache {
+        compiler.gem_cache(gem_cache);
+    }
+    for method in args.exports {
+        compiler.export(method);
+    }
+    if !args.no_cache {
+        if let Some(cache_dir) = args.cache_dir.or_else(default_cache_dir) {
+            compiler.cache(cache_dir);
+        }
+    }
+    compiler
+        .dynamic(args.dynamic)
+        .optimize(args.optimize)
+        .heap_report(args.heap_report)
+        .reproducible(args.reproducible)
+        .target(args.target.into())
+        .io_format(args.io_format.into());
+
+    let limit_options = [
+        ("--max-size", args.max_size.is_some()),
+        ("--max-memory", args.max_memory.is_some()),
+        (
+            "--allow-import-module",
+            !args.allowed_import_modules.is_empty(),
+        ),
+    ];
+    if args.target != Target::ShopifyFunction {
+        if let Some((option, _)) = limit_options.iter().find(|(_, passed)| *passed) {
+            bail!("`{option}` can only be used with `--target=shopify-function`");
+        }
+    }
+    let defaults = Limits::default();
+    compiler.limits(Limits {
+        max_size: args.max_size.unwrap_or(defaults.max_size),
+        max_memory: args.max_memory.unwrap_or(defaults.max_memory),
+        allowed_import_modules: if args.allowed_import_modules.is_empty() {
+            defaults.allowed_import_modules
+        } else {
+            args.allowed_import_modules
+        },
+    });
+    Ok(compiler)
 }
 
-fn wasi(ruby_code: &str, preload_path: Option<PathBuf>) -> Result<WasiP1Ctx> {
-    let mut wasi_builder = WasiCtxBuilder::new();
-    wasi_builder
-        .stdin(MemoryInputPipe::new(ruby_code.as_bytes().to_owned()))
-        .inherit_stdout()
-        .inherit_stderr();
-    if let Some(preload_path) = preload_path {
-        let guest_preload_path = preload_path.to_string_lossy();
-        wasi_builder
-            .env("RUVY_PRELOAD_PATH", &guest_preload_path)
-            .preopened_dir(
-                &preload_path,
-                &guest_preload_path,
-                DirPerms::READ,
-                FilePerms::READ,
-            )
-            .map(|_| ())?;
-    }
-    Ok(wasi_builder.build_p1())
+fn default_cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("RUVY_CACHE_DIR") {
+        return Some(dir.into());
+    }
+    match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => Some(PathBuf::from(dir).join("ruvy")),
+        None => Some(PathBuf::from(env::var_os("HOME")?).join(".cache/ruvy")),
+    }
 }
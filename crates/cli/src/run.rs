@@ -0,0 +1,128 @@
+use std::{env, fs, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+use ruvy::{execute, Execution, OutOfFuel, Usage};
+use wasmtime_wasi::{
+    p1::WasiP1Ctx, p2::pipe::MemoryInputPipe, DirPerms, FilePerms, WasiCtxBuilder,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct RunArgs {
+    /// Path of the Wasm module to run.
+    wasm: PathBuf,
+
+    /// Path of the engine module to link modules compiled with `--dynamic` against.
+    #[arg(long)]
+    engine: Option<PathBuf>,
+
+    /// Name of the exported function to call.
+    #[arg(long, value_name = "EXPORT", default_value = "_start")]
+    invoke: String,
+
+    /// Path of a file to use as stdin instead of inheriting it.
+    #[arg(long)]
+    input: Option<PathBuf>,
+
+    /// Host directory to make available to the module, optionally at a different
+    /// guest path with `HOST::GUEST`.
+    ///
+    /// Can be passed multiple times.
+    #[arg(long = "dir", value_name = "HOST[::GUEST]", value_parser = parse_dir)]
+    dirs: Vec<(PathBuf, String)>,
+
+    /// Environment variable to set for the module. `NAME` alone passes on the
+    /// variable's value from the host.
+    ///
+    /// Can be passed multiple times.
+    #[arg(long = "env", value_name = "NAME[=VALUE]", value_parser = parse_env)]
+    envs: Vec<(String, String)>,
+
+    /// Report the fuel consumed, the peak linear memory and the wall time of the
+    /// run on stderr.
+    ///
+    /// With a budget, the run fails when it consumes more fuel than the budget,
+    /// after reporting what it consumed.
+    #[arg(long, value_name = "BUDGET", num_args = 0..=1, require_equals = true)]
+    fuel: Option<Option<u64>>,
+
+    /// Arguments to pass to the module, available in Ruby as `ARGV`.
+    #[arg(last = true)]
+    args: Vec<String>,
+}
+
+/// Runs the function of a module selected by `args` and returns its exit
+/// code.
+///
+/// The module's stdout and stderr are streamed to the host's.
+pub async fn run(args: &RunArgs) -> Result<i32> {
+    let wasm = fs::read(&args.wasm)?;
+    let ruby_engine = args.engine.as_ref().map(fs::read).transpose()?;
+    let execution = Execution {
+        wasm: &wasm,
+        engine: ruby_engine.as_deref(),
+        function: &args.invoke,
+        fuel: args.fuel.map(|budget| budget.unwrap_or(u64::MAX)),
+    };
+    let report = match execute(&execution, wasi(args)?).await {
+        Ok(report) => report,
+        // What the run consumed before it was stopped is reported too.
+        Err(err) => {
+            if let Some(OutOfFuel { usage, .. }) = err.downcast_ref() {
+                print_usage(usage);
+            }
+            return Err(err);
+        }
+    };
+    print_usage(&report.usage);
+    Ok(report.exit_code)
+}
+
+/// Prints `usage` on stderr if fuel was counted.
+fn print_usage(usage: &Usage) {
+    if let Some(fuel) = usage.fuel {
+        let peak_memory = usage.peak_memory;
+        eprintln!("Fuel consumed: {fuel}");
+        eprintln!(
+            "Peak linear memory: {peak_memory} bytes ({:.1} MiB)",
+            peak_memory as f64 / (1024.0 * 1024.0)
+        );
+        eprintln!("Wall time: {:?}", usage.wall_time);
+    }
+}
+
+fn wasi(args: &RunArgs) -> Result<WasiP1Ctx> {
+    let mut wasi_builder = WasiCtxBuilder::new();
+    wasi_builder.inherit_stdout().inherit_stderr();
+    match &args.input {
+        Some(input) => wasi_builder.stdin(MemoryInputPipe::new(fs::read(input)?)),
+        None => wasi_builder.inherit_stdin(),
+    };
+    // Like other runtimes, the program name is the first argument.
+    wasi_builder
+        .arg(args.wasm.to_string_lossy())
+        .args(&args.args);
+    for (name, value) in &args.envs {
+        wasi_builder.env(name, value);
+    }
+    for (host, guest) in &args.dirs {
+        wasi_builder.preopened_dir(host, guest, DirPerms::all(), FilePerms::all())?;
+    }
+    Ok(wasi_builder.build_p1())
+}
+
+fn parse_dir(dir: &str) -> Result<(PathBuf, String)> {
+    match dir.split_once("::") {
+        Some((host, guest)) => Ok((host.into(), guest.to_string())),
+        None => Ok((dir.into(), dir.to_string())),
+    }
+}
+
+fn parse_env(env: &str) -> Result<(String, String)> {
+    match env.split_once('=') {
+        Some((name, value)) => Ok((name.to_string(), value.to_string())),
+        None => {
+            let value = env::var(env).map_err(|_| anyhow!("`{env}` is not set on the host"))?;
+            Ok((env.to_string(), value))
+        }
+    }
+}
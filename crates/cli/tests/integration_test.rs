@@ -1,29 +1,45 @@
-use std::{env, path::Path, process::Command, str};
+use std::{env, fs, path::Path, process::Command, str};
 
 use anyhow::{bail, Result};
 use wasmtime::{Engine, Linker, Module, Store};
 use wasmtime_wasi::{
     p1::WasiP1Ctx,
     p2::pipe::{MemoryInputPipe, MemoryOutputPipe},
-    WasiCtxBuilder,
+    I32Exit, WasiCtxBuilder,
 };
 
 #[test]
 pub fn test_hello_world() -> Result<()> {
     let wasm_path = wasm_path("hello_world");
-    run_ruvy(&wasm_path, "../../ruby_examples/hello_world.rb", None)?;
+    run_ruvy(&wasm_path, "../../ruby_examples/hello_world.rb", &[])?;
     let output = run_wasm(&wasm_path, "")?;
     assert_eq!("Hello world\n", output);
     Ok(())
 }
 
+#[test]
+pub fn test_optimize() -> Result<()> {
+    let wasm_path = wasm_path("optimize");
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args([
+            &format!("-o{wasm_path}"),
+            "--optimize",
+            "../../ruby_examples/hello_world.rb",
+        ])
+        .output()?;
+    assert!(output.status.success());
+    assert!(str::from_utf8(&output.stderr)?.contains("total"));
+    assert_eq!("Hello world\n", run_wasm(&wasm_path, "")?);
+    Ok(())
+}
+
 #[test]
 pub fn test_preludes() -> Result<()> {
     let wasm_path = wasm_path("preludes");
     run_ruvy(
         &wasm_path,
         "../../ruby_examples/use_preludes_and_stdin.rb",
-        Some("../../prelude"),
+        &["--preload=../../prelude"],
     )?;
     let output = run_wasm(&wasm_path, "this is my input")?;
     assert_eq!(
@@ -33,33 +49,626 @@ pub fn test_preludes() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_preload_order() -> Result<()> {
+    let wasm_path = wasm_path("preload_order");
+    run_ruvy(
+        &wasm_path,
+        "tests/scripts/preload_order/main.rb",
+        &[
+            "--preload=tests/scripts/preload_order/setup.rb",
+            "--preload=tests/scripts/preload_order/prelude",
+        ],
+    )?;
+    let output = run_wasm(&wasm_path, "")?;
+    assert_eq!("zeta,alpha,nested/inner\n", output);
+    Ok(())
+}
+
+#[test]
+pub fn test_metadata() -> Result<()> {
+    let wasm_path = wasm_path("metadata");
+    run_ruvy(
+        &wasm_path,
+        "../../ruby_examples/use_preludes_and_stdin.rb",
+        &[
+            "--preload=../../prelude",
+            "-I",
+            "../../prelude",
+            "--stdlib=set",
+        ],
+    )?;
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args(["metadata", &wasm_path])
+        .output()?;
+    assert!(output.status.success());
+    let metadata = str::from_utf8(&output.stdout)?;
+    assert!(metadata.contains(r#""entry_file": "use_preludes_and_stdin.rb""#));
+    assert!(metadata.contains(r#""path": "prelude/"#));
+    // Load paths are relative to the input file's directory.
+    assert!(metadata.contains(
+        r#""load_paths": [
+      "../prelude"
+    ]"#
+    ));
+    assert!(metadata.contains(
+        r#""stdlib": [
+      "set"
+    ]"#
+    ));
+    Ok(())
+}
+
+#[test]
+pub fn test_inspect() -> Result<()> {
+    let wasm_path = wasm_path("inspect");
+    run_ruvy(
+        &wasm_path,
+        "../../ruby_examples/exports.rb",
+        &["--export=run"],
+    )?;
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args(["inspect", &wasm_path])
+        .output()?;
+    assert!(output.status.success());
+    let report = str::from_utf8(&output.stdout)?;
+    assert!(report.contains("  func run\n"));
+    assert!(report.contains("wasi_snapshot_preview1::fd_write"));
+    assert!(report.contains("  exports: run\n"));
+    assert!(report.contains("entry   "));
+    Ok(())
+}
+
+#[test]
+pub fn test_heap_report() -> Result<()> {
+    let wasm_path = wasm_path("heap_report");
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args([
+            &format!("-o{wasm_path}"),
+            "--heap-report",
+            "--preload=../../prelude",
+            "../../ruby_examples/use_preludes_and_stdin.rb",
+        ])
+        .output()?;
+    assert!(output.status.success());
+    let report = str::from_utf8(&output.stderr)?;
+    assert!(report.contains("\nBy class:\n  "));
+    assert!(report.contains("  preload "));
+    assert!(report.contains("use_preludes_and_stdin.rb"));
+    assert!(!report.contains("ruvy-heap-report"));
+    let output = run_wasm(&wasm_path, "this is my input")?;
+    assert!(output.contains("this is my input"));
+    Ok(())
+}
+
+#[test]
+pub fn test_verify_reproducible() -> Result<()> {
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args([
+            "verify-reproducible",
+            "--preload=../../prelude",
+            "../../ruby_examples/use_preludes_and_stdin.rb",
+        ])
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        str::from_utf8(&output.stderr)?
+    );
+    assert!(str::from_utf8(&output.stdout)?.starts_with("The builds are identical"));
+    Ok(())
+}
+
+#[test]
+pub fn test_cache() -> Result<()> {
+    let cache_dir = format!("{}/cache", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_dir_all(&cache_dir);
+    let cache_arg = format!("--cache-dir={cache_dir}");
+    for test_name in ["cache", "cache_hit"] {
+        let wasm_path = wasm_path(test_name);
+        run_ruvy(
+            &wasm_path,
+            "tests/scripts/load_path/main.rb",
+            &["-I", "tests/scripts/load_path/lib", &cache_arg],
+        )?;
+        assert_eq!("HELLO WORLD\n", run_wasm(&wasm_path, "")?);
+    }
+    assert_eq!(1, std::fs::read_dir(&cache_dir)?.count());
+    assert_eq!(
+        std::fs::read(wasm_path("cache"))?,
+        std::fs::read(wasm_path("cache_hit"))?
+    );
+
+    // Projects whose input files are the same, but whose required files
+    // differ, don't share modules.
+    for project in ["a", "b"] {
+        let project_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("cache_{project}"));
+        fs::create_dir_all(&project_dir)?;
+        fs::write(
+            project_dir.join("main.rb"),
+            "require_relative 'helper'\nputs PROJECT\n",
+        )?;
+        fs::write(
+            project_dir.join("helper.rb"),
+            format!("PROJECT = {project:?}\n"),
+        )?;
+        let wasm_path = wasm_path(&format!("cache_{project}"));
+        run_ruvy(
+            &wasm_path,
+            project_dir.join("main.rb").to_str().unwrap(),
+            &[&cache_arg],
+        )?;
+        assert_eq!(format!("{project}\n"), run_wasm(&wasm_path, "")?);
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_load_path() -> Result<()> {
+    let wasm_path = wasm_path("load_path");
+    run_ruvy(
+        &wasm_path,
+        "tests/scripts/load_path/main.rb",
+        &["-I", "tests/scripts/load_path/lib"],
+    )?;
+    let output = run_wasm(&wasm_path, "")?;
+    assert_eq!("HELLO WORLD\n", output);
+    Ok(())
+}
+
+#[test]
+pub fn test_load_path_with_colon() -> Result<()> {
+    // The guest path of a directory in the input's directory keeps its name.
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("load_path_with_colon");
+    fs::create_dir_all(dir.join("lib:v1"))?;
+    fs::write(dir.join("main.rb"), "puts 1\n")?;
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .arg(format!("-o{}", wasm_path("load_path_with_colon")))
+        .arg("-I")
+        .arg(dir.join("lib:v1"))
+        .arg(dir.join("main.rb"))
+        .output()?;
+    assert!(!output.status.success());
+    assert!(str::from_utf8(&output.stderr)?.contains("its path contains `:`"));
+    Ok(())
+}
+
+#[test]
+pub fn test_stdlib() -> Result<()> {
+    let time_wasm_path = wasm_path("stdlib_time");
+    let wasm_path = wasm_path("stdlib");
+    run_ruvy(
+        &wasm_path,
+        "tests/scripts/stdlib.rb",
+        &["--stdlib=json,set,shellwords"],
+    )?;
+    let output = run_wasm(&wasm_path, r#"b a "c d" b"#)?;
+    assert_eq!("[\"a\",\"b\",\"c d\"]\n", output);
+
+    // `time` needs `date`, a C extension the engine doesn't include.
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args([
+            &format!("-o{time_wasm_path}"),
+            "--stdlib=time",
+            "tests/scripts/stdlib.rb",
+        ])
+        .output()?;
+    assert!(!output.status.success());
+    assert!(str::from_utf8(&output.stderr)?
+        .contains("Could not load the `time` standard library, which may depend on a C extension"));
+    Ok(())
+}
+
+#[test]
+pub fn test_native_json() -> Result<()> {
+    let wasm_path = wasm_path("native_json");
+    run_ruvy(&wasm_path, "tests/scripts/json.rb", &[])?;
+    let output = run_wasm(&wasm_path, r#"{"name": "Ruvy", "items": [1, 2.5]}"#)?;
+    assert_eq!(
+        r#"{
+  "name": "Ruvy",
+  "items": [
+    1,
+    2.5
+  ],
+  "tags": []
+}
+{"name":"Ruvy","items":[1,2.5]}
+"#,
+        output
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_io_format() -> Result<()> {
+    let wasm_path = wasm_path("io_format");
+    run_ruvy(
+        &wasm_path,
+        "../../ruby_examples/ruvy_io.rb",
+        &["--io-format=json"],
+    )?;
+    let output = run_wasm(&wasm_path, r#"{"name": "Ruvy", "items": [1, 2]}"#)?;
+    assert_eq!(r#"{"greeting":"Hello Ruvy","items":[2,4]}"#, output);
+    Ok(())
+}
+
+#[test]
+pub fn test_msgpack_io_format() -> Result<()> {
+    let wasm_path = wasm_path("msgpack_io_format");
+    run_ruvy(
+        &wasm_path,
+        "../../ruby_examples/ruvy_io.rb",
+        &["--io-format=msgpack"],
+    )?;
+    let output = run_raw(
+        None,
+        Path::new(&wasm_path),
+        "_start",
+        b"\x82\xa4name\xa4Ruvy\xa5items\x92\x01\x02",
+    )?;
+    assert_eq!(
+        b"\x82\xa8greeting\xaaHello Ruvy\xa5items\x92\x02\x04",
+        output.stdout.as_slice()
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_native_msgpack() -> Result<()> {
+    let wasm_path = wasm_path("native_msgpack");
+    run_ruvy(&wasm_path, "tests/scripts/msgpack.rb", &[])?;
+    let output = run_wasm(&wasm_path, "Ruvy")?;
+    assert_eq!(
+        r#"46
+{"name"=>"Ruvy", "items"=>[1, -1, 1099511627776, 1.5], "data"=>"\xFF"}
+"#,
+        output
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_gems() -> Result<()> {
+    let wasm_path = wasm_path("gems");
+    run_ruvy(
+        &wasm_path,
+        "tests/scripts/gems/main.rb",
+        &["--gemfile", "tests/scripts/gems/Gemfile.lock"],
+    )?;
+    let output = run_wasm(&wasm_path, "")?;
+    assert_eq!("HELLO WORLD\n", output);
+    Ok(())
+}
+
+#[test]
+pub fn test_dynamic_hello_world() -> Result<()> {
+    let engine_path = wasm_path("dynamic_hello_world_engine");
+    emit_engine(&engine_path)?;
+    let wasm_path = wasm_path("dynamic_hello_world");
+    run_ruvy(
+        &wasm_path,
+        "../../ruby_examples/hello_world.rb",
+        &["--dynamic"],
+    )?;
+    let output = run_dynamic_wasm(&engine_path, &wasm_path, "")?;
+    assert_eq!("Hello world\n", output);
+    Ok(())
+}
+
+#[test]
+pub fn test_exports() -> Result<()> {
+    let wasm_path = wasm_path("exports");
+    run_ruvy(
+        &wasm_path,
+        "../../ruby_examples/exports.rb",
+        &["--export=run", "--export=validate"],
+    )?;
+    assert_eq!("Running\n", run_wasm_export(&wasm_path, "run", "")?);
+    assert_eq!("Validating\n", run_wasm_export(&wasm_path, "validate", "")?);
+    Ok(())
+}
+
+#[test]
+pub fn test_invalid_exports() -> Result<()> {
+    for (export, error) in [
+        ("nil", "`nil` is not a valid Ruby method name"),
+        ("__method__", "`__method__` is not a valid Ruby method name"),
+        ("_start", "`_start` is reserved"),
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+            .args([
+                &format!("-o{}", wasm_path("invalid_exports")),
+                &format!("--export={export}"),
+                "../../ruby_examples/exports.rb",
+            ])
+            .output()?;
+        assert!(!output.status.success());
+        assert!(str::from_utf8(&output.stderr)?.contains(error));
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_duplicate_exports() -> Result<()> {
+    for dynamic in [false, true] {
+        let mut args = vec![
+            format!("-o{}", wasm_path("duplicate_exports")),
+            "--export=run".to_string(),
+            "--export=run".to_string(),
+            "../../ruby_examples/exports.rb".to_string(),
+        ];
+        if dynamic {
+            args.push("--dynamic".to_string());
+        }
+        let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+            .args(args)
+            .output()?;
+        assert!(!output.status.success());
+        assert!(str::from_utf8(&output.stderr)?.contains("`run` is exported more than once"));
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_dynamic_exports() -> Result<()> {
+    let engine_path = wasm_path("dynamic_exports_engine");
+    emit_engine(&engine_path)?;
+    let wasm_path = wasm_path("dynamic_exports");
+    run_ruvy(
+        &wasm_path,
+        "../../ruby_examples/exports.rb",
+        &["--dynamic", "--export=run", "--export=validate"],
+    )?;
+    let output = run(
+        Some(engine_path.as_ref()),
+        wasm_path.as_ref(),
+        "validate",
+        "",
+    )?;
+    assert_eq!("Validating\n", output.stdout);
+    Ok(())
+}
+
+#[test]
+pub fn test_shopify_function() -> Result<()> {
+    let default_limits_wasm_path = wasm_path("shopify_function_default_limits");
+    let engine_path = wasm_path("shopify_function_engine");
+    let wasm_path = wasm_path("shopify_function");
+    run_ruvy(
+        &wasm_path,
+        "../../ruby_examples/shopify_function.rb",
+        &[
+            "--target=shopify-function",
+            "--max-size=1000000000",
+            "--max-memory=1000000000",
+        ],
+    )?;
+    let output = run_wasm_export(
+        &wasm_path,
+        "run",
+        r#"{"cart":{"lines":[{"quantity":2,"price":60}]}}"#,
+    )?;
+    assert_eq!(r#"{"discounts":[{"value":{"percentage":10}}]}"#, output);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args([
+            &format!("-o{wasm_path}"),
+            "--target=shopify-function",
+            "--max-size=1024",
+            "../../ruby_examples/shopify_function.rb",
+        ])
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("the limit is 1.0 KiB"));
+    assert!(stderr.contains("Size of the module's sections:"));
+
+    // Dynamic modules import the engine, so they fit within the default
+    // limits.
+    emit_engine(&engine_path)?;
+    run_ruvy(
+        &default_limits_wasm_path,
+        "../../ruby_examples/shopify_function.rb",
+        &["--target=shopify-function", "--dynamic"],
+    )?;
+    let output = run(
+        Some(Path::new(&engine_path)),
+        Path::new(&default_limits_wasm_path),
+        "run",
+        r#"{"cart":{"lines":[{"quantity":2,"price":60}]}}"#,
+    )?;
+    assert_eq!(
+        r#"{"discounts":[{"value":{"percentage":10}}]}"#,
+        output.stdout
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_limits_without_shopify_function_target() -> Result<()> {
+    for option in [
+        "--max-size=1024",
+        "--max-memory=1024",
+        "--allow-import-module=env",
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+            .args([
+                &format!("-o{}", wasm_path("limits_without_shopify_function_target")),
+                option,
+                "../../ruby_examples/hello_world.rb",
+            ])
+            .output()?;
+        assert!(!output.status.success());
+        assert!(str::from_utf8(&output.stderr)?
+            .contains("can only be used with `--target=shopify-function`"));
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_exception() -> Result<()> {
+    let wasm_path = wasm_path("exception");
+    run_ruvy(&wasm_path, "tests/scripts/raise.rb", &[])?;
+    let output = run(None, wasm_path.as_ref(), "_start", "")?;
+    assert_eq!(1, output.exit_code);
+    // The script is mounted at a fixed path while the module is built.
+    assert!(output
+        .stderr
+        .starts_with("/ruvy/input/raise.rb:2:in `fail!': something went wrong (ArgumentError)"));
+    assert!(output.stderr.contains("\n\tfrom "));
+    Ok(())
+}
+
+#[test]
+pub fn test_syntax_error() -> Result<()> {
+    let wasm_path = wasm_path("syntax_error");
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args([&format!("-o{wasm_path}"), "tests/scripts/syntax_error.rb"])
+        .output()?;
+    assert_eq!(Some(1), output.status.code());
+    assert!(String::from_utf8(output.stderr)?.contains("(SyntaxError)"));
+    Ok(())
+}
+
+#[test]
+pub fn test_exit() -> Result<()> {
+    let wasm_path = wasm_path("exit");
+    run_ruvy(&wasm_path, "tests/scripts/exit.rb", &[])?;
+    let output = run(None, wasm_path.as_ref(), "_start", "")?;
+    assert_eq!(3, output.exit_code);
+    assert_eq!("exiting\nat_exit handler ran\n", output.stdout);
+    Ok(())
+}
+
+#[test]
+pub fn test_exit_while_building() -> Result<()> {
+    let wasm_path = wasm_path("exit_while_building");
+    let _ = std::fs::remove_file(&wasm_path);
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args([
+            &format!("-o{wasm_path}"),
+            "--preload=tests/scripts/exit_while_building.rb",
+            "../../ruby_examples/hello_world.rb",
+        ])
+        .output()?;
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("building\n", str::from_utf8(&output.stdout)?);
+    assert!(
+        str::from_utf8(&output.stderr)?.contains("script called exit while the module was built")
+    );
+    assert!(!Path::new(&wasm_path).exists());
+    Ok(())
+}
+
+#[test]
+pub fn test_abort() -> Result<()> {
+    let wasm_path = wasm_path("abort");
+    run_ruvy(&wasm_path, "tests/scripts/abort.rb", &[])?;
+    let output = run(None, wasm_path.as_ref(), "_start", "")?;
+    assert_eq!(1, output.exit_code);
+    assert_eq!("aborted\n", output.stderr);
+    Ok(())
+}
+
+#[test]
+pub fn test_run() -> Result<()> {
+    let wasm_path = wasm_path("run");
+    run_ruvy(&wasm_path, "tests/scripts/echo.rb", &[])?;
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args([
+            "run",
+            &wasm_path,
+            "--input=tests/scripts/echo_input.txt",
+            "--",
+            "a",
+            "b",
+        ])
+        .output()?;
+    assert_eq!(Some(2), output.status.code());
+    assert_eq!("a,b: hello\n", str::from_utf8(&output.stdout)?);
+    Ok(())
+}
+
+#[test]
+pub fn test_run_fuel() -> Result<()> {
+    let wasm_path = wasm_path("run_fuel");
+    run_ruvy(&wasm_path, "../../ruby_examples/hello_world.rb", &[])?;
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args(["run", "--fuel", &wasm_path])
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!("Hello world\n", str::from_utf8(&output.stdout)?);
+    let stderr = str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("Fuel consumed: "));
+    assert!(stderr.contains("Peak linear memory: "));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args(["run", "--fuel=1000", &wasm_path])
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("Fuel consumed: 1000\n"));
+    assert!(stderr.contains("Exceeded the fuel budget of 1000"));
+    Ok(())
+}
+
+#[test]
+pub fn test_run_out_of_fuel() -> Result<()> {
+    let wat_path = format!("{}/run_out_of_fuel.wat", env!("CARGO_TARGET_TMPDIR"));
+    fs::write(
+        &wat_path,
+        r#"(module
+            (memory 2)
+            (func (export "_start") (loop (br 0))))"#,
+    )?;
+    let output = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args(["run", "--fuel=1000", &wat_path])
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("Fuel consumed: 1000\n"));
+    assert!(stderr.contains("Peak linear memory: 131072 bytes (0.1 MiB)\n"));
+    assert!(stderr.contains("Wall time: "));
+    assert!(stderr.contains("Exceeded the fuel budget of 1000"));
+    Ok(())
+}
+
 struct Context {
     wasi: WasiP1Ctx,
     out_stream: MemoryOutputPipe,
+    err_stream: MemoryOutputPipe,
 }
 
 impl Context {
     fn new(input: &[u8]) -> Context {
         let out_stream = MemoryOutputPipe::new(usize::MAX);
+        let err_stream = MemoryOutputPipe::new(usize::MAX);
         Context {
             wasi: WasiCtxBuilder::new()
                 .stdin(MemoryInputPipe::new(input.to_vec()))
                 .stdout(out_stream.clone())
+                .stderr(err_stream.clone())
                 .build_p1(),
             out_stream,
+            err_stream,
         }
     }
 }
 
+struct Output<T = String> {
+    stdout: T,
+    stderr: T,
+    exit_code: i32,
+}
+
 fn wasm_path(test_name: &str) -> String {
     format!("{}/{test_name}.wasm", env!("CARGO_TARGET_TMPDIR"))
 }
 
-fn run_ruvy(wasm_path: &str, input_path: &str, preload: Option<&str>) -> Result<()> {
+fn run_ruvy(wasm_path: &str, input_path: &str, extra_args: &[&str]) -> Result<()> {
     let mut args = vec![format!("-o{wasm_path}")];
-    if let Some(preload) = preload {
-        args.push(format!("--preload={preload}"));
-    }
+    args.extend(extra_args.iter().map(|arg| arg.to_string()));
     args.push(input_path.to_string());
 
     let status = Command::new(env!("CARGO_BIN_EXE_ruvy"))
@@ -71,22 +680,82 @@ fn run_ruvy(wasm_path: &str, input_path: &str, preload: Option<&str>) -> Result<
     Ok(())
 }
 
+fn emit_engine(engine_path: &str) -> Result<()> {
+    let status = Command::new(env!("CARGO_BIN_EXE_ruvy"))
+        .args(["emit-engine", &format!("-o{engine_path}")])
+        .status()?;
+    if !status.success() {
+        bail!("Failed to emit engine");
+    }
+    Ok(())
+}
+
 fn run_wasm(wasm_path: impl AsRef<Path>, input: &str) -> Result<String> {
+    Ok(run(None, wasm_path.as_ref(), "_start", input)?.stdout)
+}
+
+fn run_wasm_export(wasm_path: impl AsRef<Path>, export: &str, input: &str) -> Result<String> {
+    Ok(run(None, wasm_path.as_ref(), export, input)?.stdout)
+}
+
+fn run_dynamic_wasm(
+    engine_path: impl AsRef<Path>,
+    wasm_path: impl AsRef<Path>,
+    input: &str,
+) -> Result<String> {
+    Ok(run(
+        Some(engine_path.as_ref()),
+        wasm_path.as_ref(),
+        "_start",
+        input,
+    )?
+    .stdout)
+}
+
+fn run(engine_path: Option<&Path>, wasm_path: &Path, export: &str, input: &str) -> Result<Output> {
+    let output = run_raw(engine_path, wasm_path, export, input.as_bytes())?;
+    Ok(Output {
+        stdout: String::from_utf8(output.stdout)?,
+        stderr: String::from_utf8(output.stderr)?,
+        exit_code: output.exit_code,
+    })
+}
+
+fn run_raw(
+    engine_path: Option<&Path>,
+    wasm_path: &Path,
+    export: &str,
+    input: &[u8],
+) -> Result<Output<Vec<u8>>> {
     let engine = Engine::default();
     let mut linker = Linker::new(&engine);
     wasmtime_wasi::p1::add_to_linker_sync(&mut linker, |cx: &mut Context| &mut cx.wasi)?;
-    let mut store = Store::new(&engine, Context::new(input.as_bytes()));
+    let mut store = Store::new(&engine, Context::new(input));
+
+    if let Some(engine_path) = engine_path {
+        let ruby_engine = Module::from_file(&engine, engine_path)?;
+        let instance = linker.instantiate(&mut store, &ruby_engine)?;
+        linker.instance(&mut store, "ruvy_engine_v1", instance)?;
+    }
 
     let module = Module::from_file(&engine, wasm_path)?;
-    linker
+    let result = linker
         .instantiate(&mut store, &module)?
-        .get_typed_func::<(), ()>(&mut store, "_start")?
-        .call(&mut store, ())?;
+        .get_typed_func::<(), ()>(&mut store, export)?
+        .call(&mut store, ());
+    let exit_code = match result {
+        Ok(()) => 0,
+        Err(err) => match err.downcast_ref::<I32Exit>() {
+            Some(I32Exit(code)) => *code,
+            None => return Err(err),
+        },
+    };
 
     let context = store.into_data();
     drop(context.wasi);
-    let output = context.out_stream.contents();
-    let output = String::from_utf8(output.to_vec())?;
-
-    Ok(output)
+    Ok(Output {
+        stdout: context.out_stream.contents().to_vec(),
+        stderr: context.err_stream.contents().to_vec(),
+        exit_code,
+    })
 }
@@ -0,0 +1 @@
+abort "aborted"
@@ -0,0 +1,2 @@
+puts "#{ARGV.join(",")}: #{STDIN.read}"
+exit ARGV.size
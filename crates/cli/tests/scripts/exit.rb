@@ -0,0 +1,4 @@
+at_exit { puts "at_exit handler ran" }
+puts "exiting"
+exit 3
+puts "unreachable"
@@ -0,0 +1,2 @@
+puts "building"
+exit
@@ -0,0 +1 @@
+puts Greeting.greet("world")
@@ -0,0 +1,5 @@
+require "json"
+
+input = JSON.parse(STDIN.read, symbolize_names: true)
+puts JSON.pretty_generate({ name: input[:name], items: input[:items], tags: [] })
+puts input.to_json
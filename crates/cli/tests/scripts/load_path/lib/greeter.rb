@@ -0,0 +1,7 @@
+module Greeter
+  def self.greet(name)
+    # Only required when the module runs.
+    require "greeting"
+    "#{GREETING} #{name}"
+  end
+end
@@ -0,0 +1 @@
+GREETING = "Hello"
@@ -0,0 +1,13 @@
+require "greeter"
+require_relative "helpers/shout"
+
+begin
+  require "optional_dependency"
+rescue LoadError
+end
+
+USAGE = <<~TEXT
+  require "not_a_require"
+TEXT
+
+puts shout(Greeter.greet("world"))
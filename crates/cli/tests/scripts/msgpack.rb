@@ -0,0 +1,5 @@
+require "msgpack"
+
+packed = { "name" => STDIN.read, "items" => [1, -1, 2**40, 1.5], "data" => "\xFF".b }.to_msgpack
+puts packed.bytesize
+p MessagePack.unpack(packed)
@@ -0,0 +1 @@
+puts LOADED.join(",")
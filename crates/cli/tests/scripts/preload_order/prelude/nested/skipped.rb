@@ -0,0 +1 @@
+raise "excluded files should not be preloaded"
@@ -0,0 +1 @@
+LOADED = []
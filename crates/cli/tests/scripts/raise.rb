@@ -0,0 +1,5 @@
+def fail!
+  raise ArgumentError, "something went wrong"
+end
+
+fail!
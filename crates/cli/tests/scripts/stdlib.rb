@@ -0,0 +1,6 @@
+require "json"
+require "set"
+require "shellwords"
+
+names = Set.new(STDIN.read.shellsplit)
+puts JSON.generate(names.sort)
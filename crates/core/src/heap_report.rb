@@ -0,0 +1,90 @@
+# Accounts for the Ruby objects retained by the snapshot.
+#
+# Live objects are measured after each step of the initialization, so what a
+# step retains is attributed to the library or file it loaded. Sizes come from
+# `ObjectSpace.memsize_of` when the `objspace` extension is available, and are
+# estimated from object slots and contents otherwise.
+#
+# The report is written as tab-separated lines to `heap-report.tsv`, in the
+# directory named by `RUVY_REPORT_DIR`, which the host reads back.
+module RuvyHeapReport
+  SLOT_SIZE = GC::INTERNAL_CONSTANTS[:RVALUE_SIZE] || 40
+  VALUE_SIZE = 1.size
+  CLASS_OF = Kernel.instance_method(:class)
+  NAME_OF = Module.instance_method(:name)
+
+  @memsize = begin
+    require "objspace"
+    true
+  rescue LoadError
+    false
+  end
+  @sources = []
+  @last = [0, 0]
+
+  class << self
+    # Records what was retained since the previous step as coming from the
+    # source `name` of the given `kind`.
+    def step(kind, name)
+      objects, bytes = totals
+      @sources << [kind, name, objects - @last[0], bytes - @last[1]]
+      @last = [objects, bytes]
+    end
+
+    # Writes the report and removes this module, so it isn't part of the
+    # snapshot.
+    def finish
+      classes = Hash.new { |hash, name| hash[name] = [0, 0] }
+      each_live_object do |object, bytes|
+        usage = classes[class_name(object)]
+        usage[0] += 1
+        usage[1] += bytes
+      end
+
+      lines = [["estimated", !@memsize]]
+      classes.each { |name, (objects, bytes)| lines << ["class", name, objects, bytes] }
+      @sources.each { |source| lines << ["source", *source] }
+      File.write(
+        File.join(ENV.fetch("RUVY_REPORT_DIR"), "heap-report.tsv"),
+        lines.map { |line| line.map { |field| field.to_s.tr("\t\n", "  ") }.join("\t") + "\n" }.join
+      )
+
+      Object.send(:remove_const, :RuvyHeapReport)
+      GC.start
+    end
+
+    private
+
+    def totals
+      objects = 0
+      total = 0
+      each_live_object do |_object, bytes|
+        objects += 1
+        total += bytes
+      end
+      [objects, total]
+    end
+
+    def each_live_object
+      GC.start
+      ObjectSpace.each_object { |object| yield object, size_of(object) }
+    end
+
+    def size_of(object)
+      return ObjectSpace.memsize_of(object) if @memsize
+
+      # Strings, arrays and hashes are the objects most likely to store their
+      # contents outside of their slot.
+      case object
+      when String then SLOT_SIZE + object.bytesize
+      when Array then SLOT_SIZE + object.size * VALUE_SIZE
+      when Hash then SLOT_SIZE + object.size * 3 * VALUE_SIZE
+      else SLOT_SIZE
+      end
+    end
+
+    def class_name(object)
+      NAME_OF.bind_call(CLASS_OF.bind_call(object)) || "(anonymous class)"
+    end
+  end
+end
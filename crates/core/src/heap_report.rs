@@ -0,0 +1,49 @@
+use anyhow::Result;
+use std::env;
+
+use crate::runtime;
+
+/// Ruby code accounting for the objects retained by the snapshot.
+const ACCOUNTING: &str = include_str!("heap_report.rb");
+
+/// Attributes the Ruby objects retained by the snapshot to the steps of the
+/// initialization that allocated them, when `RUVY_HEAP_REPORT` is set.
+///
+/// The report is written to the directory named by `RUVY_REPORT_DIR` by
+/// [`HeapReport::finish`] for the host to parse.
+pub struct HeapReport {
+    enabled: bool,
+}
+
+impl HeapReport {
+    /// Starts the report, attributing what the VM retained so far to Ruby
+    /// itself.
+    pub fn start() -> Result<Self> {
+        let report = Self {
+            enabled: env::var_os("RUVY_HEAP_REPORT").is_some(),
+        };
+        if report.enabled {
+            runtime::eval(ACCOUNTING)?;
+        }
+        report.step("ruby", "Ruby VM")?;
+        Ok(report)
+    }
+
+    /// Attributes what was retained since the previous step to the source
+    /// `name`, e.g. a preloaded file, of the given `kind`.
+    pub fn step(&self, kind: &str, name: &str) -> Result<()> {
+        if self.enabled {
+            runtime::call_module_method(c"RuvyHeapReport", c"step", &[kind, name])?;
+        }
+        Ok(())
+    }
+
+    /// Breaks down the retained objects by class, writes the report and
+    /// removes the accounting code from the VM.
+    pub fn finish(self) -> Result<()> {
+        if self.enabled {
+            runtime::call_module_method(c"RuvyHeapReport", c"finish", &[])?;
+        }
+        Ok(())
+    }
+}
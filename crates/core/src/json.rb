@@ -0,0 +1,60 @@
+# The parts of the `json` standard library scripts use, implemented by
+# `JSON::Native` in Rust, which also defines the exception classes.
+#
+# `json` is marked as loaded, so `require "json"` keeps working without the
+# standard library.
+module JSON
+  class << self
+    def parse(source, opts = nil)
+      check_options(opts, %i[symbolize_names])
+      Native.parse(source.to_str, !!(opts && opts[:symbolize_names]))
+    end
+    alias_method :parse!, :parse
+
+    def load(source, opts = nil)
+      source = source.read if source.respond_to?(:read)
+      return nil if source.nil? || source.empty?
+
+      parse(source, opts)
+    end
+
+    def generate(obj, opts = nil)
+      check_options(opts, [])
+      Native.generate(obj, false)
+    end
+
+    def pretty_generate(obj, opts = nil)
+      check_options(opts, [])
+      Native.generate(obj, true)
+    end
+
+    def dump(obj, io = nil)
+      json = generate(obj)
+      return json unless io
+
+      io.write(json)
+      io
+    end
+
+    private
+
+    # Raises for the options this implementation doesn't support, instead of
+    # ignoring them.
+    def check_options(opts, supported)
+      return if opts.nil?
+
+      unsupported = opts.to_h.keys.map(&:to_sym) - supported
+      return if unsupported.empty?
+
+      raise ArgumentError, "unsupported JSON options: #{unsupported.join(", ")}"
+    end
+  end
+end
+
+class Object
+  def to_json(*)
+    JSON.generate(self)
+  end
+end
+
+$LOADED_FEATURES << "json.rb"
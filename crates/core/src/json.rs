@@ -0,0 +1,352 @@
+use std::{ffi::CString, fmt, io::Write, str};
+
+use anyhow::{Error, Result};
+use ruvy_wasm_sys::{
+    rb_ary_entry, rb_ary_new_capa, rb_ary_push, rb_cstr2inum, rb_hash_aset, rb_hash_new,
+    ruby_value_type_RUBY_T_ARRAY, ruby_value_type_RUBY_T_BIGNUM, ruby_value_type_RUBY_T_FALSE,
+    ruby_value_type_RUBY_T_FIXNUM, ruby_value_type_RUBY_T_FLOAT, ruby_value_type_RUBY_T_HASH,
+    ruby_value_type_RUBY_T_NIL, ruby_value_type_RUBY_T_STRING, ruby_value_type_RUBY_T_SYMBOL,
+    ruby_value_type_RUBY_T_TRUE, ruvy_rarray_len, VALUE,
+};
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+
+use crate::{
+    native::{self, function, Exception, Module},
+    runtime::{self, protect},
+    value::{IntoValue, Symbol, Value},
+};
+
+/// Ruby code defining the `JSON` methods on top of `JSON::Native`.
+const JSON: &str = include_str!("json.rb");
+
+/// Key of the map serde_json represents numbers with when its
+/// `arbitrary_precision` feature is enabled, with the number's text as value.
+const NUMBER_TOKEN: &str = "$serde_json::private::Number";
+
+/// Maximum depth of the arrays and hashes generated, like the `max_nesting`
+/// default of the `json` standard library.
+const MAX_NESTING: usize = 100;
+
+/// Defines the `JSON` module, which takes the place of the `json` standard
+/// library.
+pub fn define() -> Result<()> {
+    let json = native::define_module("JSON")?;
+    let json_error =
+        json.define_class("JSONError", Some(native::lookup_module("StandardError")?))?;
+    let parser_error = json.define_class("ParserError", Some(json_error))?;
+    json.define_class("NestingError", Some(parser_error))?;
+    json.define_class("GeneratorError", Some(json_error))?;
+    let native = json.define_module("Native")?;
+    native.define_module_function("parse", function!(parse))?;
+    native.define_module_function("generate", function!(generate))?;
+    runtime::eval(JSON)?;
+    Ok(())
+}
+
+/// Parses the JSON document `source` into Ruby objects, with `Symbol` keys
+/// instead of `String` keys if `symbolize_names` is set.
+pub fn parse(source: Value, symbolize_names: bool) -> Result<Value> {
+    let source = source.string_bytes()?;
+    // The objects are only referenced from Rust until the document is parsed.
+    runtime::without_gc(|| {
+        let mut deserializer = serde_json::Deserializer::from_slice(source);
+        let value = Parser { symbolize_names }.deserialize(&mut deserializer)?;
+        deserializer.end()?;
+        Ok(value)
+    })
+    .map_err(|err: serde_json::Error| error("JSON::ParserError", err.to_string()))
+}
+
+/// Builds Ruby objects from the values serde reads.
+#[derive(Clone, Copy)]
+struct Parser {
+    symbolize_names: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for Parser {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Parser {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::NIL)
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        new_value(v)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        new_value(v)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        new_value(v)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        new_value(v)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        new_value(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let capacity = seq.size_hint().unwrap_or_default();
+        let array = ruby(|| unsafe { rb_ary_new_capa(capacity as _) })?;
+        while let Some(element) = seq.next_element_seed(self)? {
+            unsafe { rb_ary_push(array.as_raw(), element.as_raw()) };
+        }
+        Ok(array)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut hash = None;
+        while let Some(key) = map.next_key::<String>()? {
+            // Numbers are read as maps, see `NUMBER_TOKEN`.
+            if hash.is_none() && key == NUMBER_TOKEN {
+                return number(&map.next_value::<String>()?);
+            }
+            let hash = match hash {
+                Some(hash) => hash,
+                None => *hash.insert(ruby(|| unsafe { rb_hash_new() })?),
+            };
+            let key = if self.symbolize_names {
+                new_value(Symbol(key))?
+            } else {
+                new_value(key)?
+            };
+            let value = map.next_value_seed(self)?;
+            unsafe { rb_hash_aset(hash.as_raw(), key.as_raw(), value.as_raw()) };
+        }
+        match hash {
+            Some(hash) => Ok(hash),
+            None => ruby(|| unsafe { rb_hash_new() }),
+        }
+    }
+}
+
+/// Converts the text of a JSON number into a `Float`, or into an `Integer`
+/// like `Integer()` does, so integers keep their value whatever their size.
+fn number<E: de::Error>(text: &str) -> Result<Value, E> {
+    if text.contains(['.', 'e', 'E']) {
+        return new_value(text.parse::<f64>().map_err(E::custom)?);
+    }
+    let text = CString::new(text).map_err(E::custom)?;
+    ruby(|| unsafe { rb_cstr2inum(text.as_ptr(), 10) })
+}
+
+fn new_value<E: de::Error>(value: impl IntoValue) -> Result<Value, E> {
+    Value::new(value).map_err(|err| E::custom(format!("{err:#}")))
+}
+
+/// Creates an object with the C API, which raises if the VM is out of memory.
+fn ruby<E: de::Error>(f: impl FnOnce() -> VALUE) -> Result<Value, E> {
+    protect(|| unsafe { Value::from_raw(f()) }).map_err(|err| E::custom(format!("{err:#}")))
+}
+
+/// Generates the JSON document representing `value`, formatted with
+/// newlines and indentation if `pretty` is set, like `JSON.pretty_generate`.
+pub fn generate(value: Value, pretty: bool) -> Result<Value> {
+    let mut generator = Generator {
+        json: vec![],
+        pretty,
+        depth: 0,
+    };
+    generator.write(value)?;
+    Value::new(String::from_utf8(generator.json)?)
+}
+
+struct Generator {
+    json: Vec<u8>,
+    pretty: bool,
+    /// Number of arrays and hashes containing the value being written.
+    depth: usize,
+}
+
+impl Generator {
+    #[allow(non_upper_case_globals)]
+    fn write(&mut self, value: Value) -> Result<()> {
+        match value.ruby_type() {
+            ruby_value_type_RUBY_T_NIL => self.json.extend_from_slice(b"null"),
+            ruby_value_type_RUBY_T_TRUE => self.json.extend_from_slice(b"true"),
+            ruby_value_type_RUBY_T_FALSE => self.json.extend_from_slice(b"false"),
+            ruby_value_type_RUBY_T_FIXNUM => write!(self.json, "{}", value.to::<i64>()?)?,
+            ruby_value_type_RUBY_T_BIGNUM => self.write_raw(value.call("to_s", &[])?)?,
+            ruby_value_type_RUBY_T_FLOAT => {
+                if !value.to::<f64>()?.is_finite() {
+                    return Err(error(
+                        "JSON::GeneratorError",
+                        format!("{} not allowed in JSON", value.inspect()?),
+                    ));
+                }
+                // Ruby formats floats differently from Rust, e.g. `1.0e+20`.
+                self.write_raw(value.call("to_s", &[])?)?;
+            }
+            ruby_value_type_RUBY_T_STRING => self.write_string(value)?,
+            ruby_value_type_RUBY_T_SYMBOL => self.write_str(&value.to::<Symbol>()?.0)?,
+            ruby_value_type_RUBY_T_ARRAY => self.write_array(value)?,
+            ruby_value_type_RUBY_T_HASH => self.write_hash(value)?,
+            _ if has_own_to_json(value)? => self.write_raw(value.call("to_json", &[])?)?,
+            _ => self.write_string(value.call("to_s", &[])?)?,
+        }
+        Ok(())
+    }
+
+    fn write_array(&mut self, array: Value) -> Result<()> {
+        let len = unsafe { ruvy_rarray_len(array.as_raw()) };
+        self.nested(b'[', b']', len == 0, |generator| {
+            for index in 0..len {
+                if index > 0 {
+                    generator.json.push(b',');
+                }
+                generator.newline();
+                let element = unsafe { Value::from_raw(rb_ary_entry(array.as_raw(), index)) };
+                generator.write(element)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_hash(&mut self, hash: Value) -> Result<()> {
+        let pairs = hash.pairs()?;
+        self.nested(b'{', b'}', pairs.is_empty(), |generator| {
+            for (index, (key, value)) in pairs.into_iter().enumerate() {
+                if index > 0 {
+                    generator.json.push(b',');
+                }
+                generator.newline();
+                if key.ruby_type() == ruby_value_type_RUBY_T_STRING {
+                    generator.write_string(key)?;
+                } else if key.ruby_type() == ruby_value_type_RUBY_T_SYMBOL {
+                    generator.write_str(&key.to::<Symbol>()?.0)?;
+                } else {
+                    generator.write_string(key.call("to_s", &[])?)?;
+                }
+                generator.json.push(b':');
+                if generator.pretty {
+                    generator.json.push(b' ');
+                }
+                generator.write(value)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes an array or hash between `open` and `close`, whose contents are
+    /// written by `f`.
+    fn nested(
+        &mut self,
+        open: u8,
+        close: u8,
+        empty: bool,
+        f: impl FnOnce(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        if self.depth == MAX_NESTING {
+            return Err(error(
+                "JSON::NestingError",
+                format!("nesting of {} is too deep", MAX_NESTING + 1),
+            ));
+        }
+        self.json.push(open);
+        if !empty {
+            self.depth += 1;
+            f(self)?;
+            self.depth -= 1;
+            self.newline();
+        }
+        self.json.push(close);
+        Ok(())
+    }
+
+    /// Starts a new line indented to the current depth when pretty printing.
+    fn newline(&mut self) {
+        if self.pretty {
+            self.json.push(b'\n');
+            self.json.resize(self.json.len() + self.depth * 2, b' ');
+        }
+    }
+
+    fn write_string(&mut self, string: Value) -> Result<()> {
+        let Ok(string) = str::from_utf8(string.string_bytes()?) else {
+            return Err(error(
+                "JSON::GeneratorError",
+                "source sequence is illegal/malformed utf-8".to_string(),
+            ));
+        };
+        self.write_str(string)
+    }
+
+    fn write_str(&mut self, string: &str) -> Result<()> {
+        serde_json::to_writer(&mut self.json, string)?;
+        Ok(())
+    }
+
+    /// Writes the JSON returned by a `to_json` or `to_s` method as is.
+    fn write_raw(&mut self, json: Value) -> Result<()> {
+        self.json.extend_from_slice(json.string_bytes()?);
+        Ok(())
+    }
+}
+
+/// Whether `value` has a `to_json` method other than the generic one of
+/// `Object`, which generates its `to_s`.
+fn has_own_to_json(value: Value) -> Result<bool> {
+    let method = value.call("method", &[Value::new(Symbol("to_json".to_string()))?])?;
+    Ok(method.call("owner", &[])? != Module::object().as_value())
+}
+
+/// Returns an error raising the exception `class` defined by [`define`].
+fn error(class: &str, message: String) -> Error {
+    match native::lookup_module(class) {
+        Ok(class) => Exception::new(class, message).into(),
+        Err(err) => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{eval, init_ruby};
+
+    #[test]
+    fn test_parse_generate() -> Result<()> {
+        init_ruby();
+        define()?;
+        assert_eq!(
+            r#"{"a":[1,2.5,null,true],"b":"\u0000é"}"#,
+            eval(r#"JSON.generate(JSON.parse('{"a": [1, 2.5, null, true], "b": "\u0000é"}'))"#)?
+                .to::<String>()?
+        );
+        assert_eq!(
+            "[:a]",
+            eval(r#"JSON.parse('{"a": 1}', symbolize_names: true).keys.inspect"#)?.inspect()?
+        );
+        assert_eq!(
+            "[18446744073709551616, -18446744073709551617, 0.5]",
+            eval("JSON.parse('[18446744073709551616, -18446744073709551617, 0.5]').inspect")?
+                .to::<String>()?
+        );
+        assert_eq!(
+            "ArgumentError",
+            eval("begin; JSON.generate([], indent: '  '); rescue => e; e.class.name; end")?
+                .to::<String>()?
+        );
+        assert_eq!(
+            "JSON::ParserError",
+            eval("begin; JSON.parse('{'); rescue => e; e.class.name; end")?.to::<String>()?
+        );
+        Ok(())
+    }
+}
@@ -1,28 +1,336 @@
+mod heap_report;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+mod native;
+mod requires;
 mod runtime;
+mod ruvy_io;
+mod sorted_dir;
+mod value;
 
-use runtime::cleanup_ruby;
-use std::{env, io, sync::OnceLock};
+use anyhow::{Context, Result};
+use heap_report::HeapReport;
+use runtime::{cleanup_ruby, Exit};
+use sorted_dir::SortedDir;
+use std::{
+    alloc, collections::HashSet, env, ffi::c_void, fs, io, path::Path, process, ptr, slice, str,
+    sync::OnceLock,
+};
+use value::Value;
 
-static USER_CODE: OnceLock<String> = OnceLock::new();
+/// Instruction sequence of the user code, compiled during initialization.
+static USER_ISEQ: OnceLock<Value> = OnceLock::new();
+static EXPORTS: OnceLock<Vec<String>> = OnceLock::new();
 
 fn main() {
-    let code = USER_CODE.get().unwrap();
-    runtime::eval(code).unwrap();
-    cleanup_ruby().unwrap();
+    // The first argument is the program name, which isn't part of `ARGV`.
+    exit_on_error(runtime::set_argv(env::args().skip(1)));
+    finish(runtime::eval_iseq(*USER_ISEQ.get().unwrap()));
 }
 
 #[export_name = "wizer-initialize"]
 pub extern "C" fn load_user_code() {
     let _wasm_ctx = WasmCtx::new();
 
+    match initialize() {
+        Ok(()) => {}
+        // The snapshot can't be taken halfway through the code, so exiting
+        // fails the build even with a status of 0.
+        Err(err) => match err.downcast_ref::<Exit>() {
+            Some(Exit(status)) => {
+                eprintln!("script called exit while the module was built");
+                process::exit(if *status == 0 { 1 } else { *status });
+            }
+            None => exit_on_error(Err(err)),
+        },
+    }
+}
+
+/// Loads the user code and everything it needs into the VM before the
+/// snapshot is taken.
+fn initialize() -> Result<()> {
     runtime::init_ruby();
+    #[cfg(feature = "json")]
+    json::define()?;
+    #[cfg(feature = "msgpack")]
+    msgpack::define()?;
+    ruvy_io::define()?;
+    let heap_report = HeapReport::start()?;
+    let sorted_dir = SortedDir::start()?;
+
+    if let Ok(load_path) = env::var("RUVY_LOAD_PATH") {
+        // The host rejects paths containing `:`.
+        runtime::add_load_paths(load_path.split(':'))?;
+    }
+
+    if let Ok(libraries) = env::var("RUVY_STDLIB") {
+        for library in libraries.split(',') {
+            // The `json` standard library needs a C extension, so `JSON` only
+            // comes from the engine.
+            #[cfg(not(feature = "json"))]
+            if library == "json" {
+                anyhow::bail!(
+                    "`json` needs the engine's `json` feature, which it was built without"
+                );
+            }
+            runtime::require(library).map_err(|err| {
+                let context = if runtime::is_load_error(&err) {
+                    format!("Could not load the `{library}` standard library, which may depend on a C extension the engine doesn't include")
+                } else {
+                    format!("Could not load the `{library}` standard library")
+                };
+                err.context(context)
+            })?;
+            heap_report.step("stdlib", library)?;
+        }
+    }
+
+    // Gems come in dependency order, so each gem's dependencies are loaded
+    // before it.
+    if let Ok(features) = env::var("RUVY_GEMS") {
+        for feature in features.split(',').filter(|feature| !feature.is_empty()) {
+            runtime::require(feature)
+                .with_context(|| format!("Could not activate the gem providing `{feature}`"))?;
+            heap_report.step("gem", feature)?;
+        }
+    }
+
+    let mut preloaded = vec![];
+    if let Ok(preload_paths) = env::var("RUVY_PRELOAD_PATH") {
+        // The host rejects paths containing `:`.
+        for preload_path in preload_paths.split(':') {
+            for file in ruvy_preload::files(Path::new(preload_path))? {
+                runtime::preload_file(&file)?;
+                heap_report.step("preload", &file.to_string_lossy())?;
+                preloaded.push(file.to_string_lossy().into_owned());
+            }
+        }
+    }
+
+    let code = io::read_to_string(io::stdin()).unwrap();
+    // Like CRuby, call scripts read from stdin `-` when there is no path.
+    let path = env::var("RUVY_INPUT_PATH").unwrap_or_else(|_| "-".to_string());
+    require_dependencies(&code, &path, preloaded, &heap_report)?;
+    // Compiling reports syntax errors when the module is built, and saves
+    // parsing the code every time the module runs.
+    let iseq = runtime::compile(&code, &path)?;
+    if let Ok(exports) = env::var("RUVY_EXPORTS") {
+        // Exported methods are called on the snapshotted VM so they need to
+        // be defined before the snapshot is taken.
+        runtime::eval_iseq(iseq)?;
+        EXPORTS
+            .set(exports.split(',').map(String::from).collect())
+            .unwrap();
+    }
+    heap_report.step("entry", &path)?;
+    heap_report.finish()?;
+    sorted_dir.finish()?;
+    ruvy_io::finish();
+    report_loaded_features()?;
+    USER_ISEQ.set(iseq).unwrap();
+    reset_wasi_libc();
+    Ok(())
+}
+
+/// Writes the files loaded with `require` to `loaded-features.txt`, one per
+/// line, in the directory named by `RUVY_REPORT_DIR`, so the host can tell
+/// which files the snapshot depends on.
+fn report_loaded_features() -> Result<()> {
+    let Ok(report_dir) = env::var("RUVY_REPORT_DIR") else {
+        return Ok(());
+    };
+    let mut features = String::new();
+    for feature in runtime::loaded_features()? {
+        features.push_str(&feature);
+        features.push('\n');
+    }
+    fs::write(Path::new(&report_dir).join("loaded-features.txt"), features)
+        .context("Could not write the loaded features")
+}
+
+extern "C" {
+    fn __wasilibc_reset_preopens();
+    fn __wasilibc_deinitialize_environ();
+}
+
+/// Makes wasi-libc forget the directories and environment variables the
+/// module was built with, which refer to the build host and whose file
+/// descriptors are meaningless once the snapshot runs elsewhere.
+///
+/// wasi-libc looks up the runtime's preopened directories and environment
+/// variables again the next time they're used, so `ENV` and file access in
+/// Ruby see the runtime's instead.
+fn reset_wasi_libc() {
+    unsafe {
+        __wasilibc_reset_preopens();
+        __wasilibc_deinitialize_environ();
+    }
+}
+
+/// Loads the files required by the user code so they're part of the snapshot.
+///
+/// When the user code runs, its `require` calls find the files in
+/// `$LOADED_FEATURES` instead of the filesystem, which isn't available then.
+/// The requires of preloaded files and of the user's files they load are
+/// loaded too, since the ones in methods or blocks only run later.
+fn require_dependencies(
+    code: &str,
+    path: &str,
+    preloaded: Vec<String>,
+    heap_report: &HeapReport,
+) -> Result<()> {
+    requires::define()?;
+    let mut searched: HashSet<String> = preloaded.iter().cloned().collect();
+    let mut pending = vec![(path.to_string(), code.to_string())];
+    for file in preloaded.into_iter().rev() {
+        let code = fs::read_to_string(&file).with_context(|| format!("Could not read {file}"))?;
+        pending.push((file, code));
+    }
+    while let Some((path, code)) = pending.pop() {
+        let requires = requires::find(&code, &path)?;
+        for feature in &requires.top_level {
+            runtime::require(feature)?;
+            heap_report.step("require", feature)?;
+        }
+        for feature in &requires.nested {
+            match runtime::require(feature) {
+                Ok(()) => heap_report.step("require", feature)?,
+                // Requires that don't run unconditionally may load optional
+                // dependencies, which are missing when the code runs too.
+                Err(err) if runtime::is_load_error(&err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        for feature in runtime::loaded_features()? {
+            if requires::is_user_file(&feature) && searched.insert(feature.clone()) {
+                let code = fs::read_to_string(&feature)
+                    .with_context(|| format!("Could not read {feature}"))?;
+                pending.push((feature, code));
+            }
+        }
+    }
+    requires::remove()
+}
+
+/// Calls the top-level Ruby method recorded at `index` during
+/// initialization and cleans up the VM afterwards.
+///
+/// The CLI generates a Wasm export for each method that calls this function.
+#[export_name = "ruvy_call_export"]
+pub extern "C" fn call_export(index: u32) {
+    let _wasm_ctx = WasmCtx::new();
+
+    let method = &EXPORTS.get().unwrap()[index as usize];
+    finish(runtime::call(method));
+}
+
+/// Evaluates the Ruby source code stored at `code_ptr` and cleans up the VM
+/// afterwards.
+///
+/// This is the entrypoint used by modules compiled in dynamic mode, which
+/// copy their Ruby source code into this module's memory using
+/// `canonical_abi_realloc`.
+///
+/// # Safety
+///
+/// `code_ptr` must point to `code_len` bytes of UTF-8 in linear memory.
+#[export_name = "ruvy_eval"]
+pub unsafe extern "C" fn eval_code(code_ptr: *const u8, code_len: usize) {
+    let _wasm_ctx = WasmCtx::new();
+
+    finish(runtime::eval(str_from_raw_parts(code_ptr, code_len)));
+}
+
+/// Evaluates the Ruby source code stored at `code_ptr`, calls the top-level
+/// method named by the string stored at `method_ptr` and cleans up the VM
+/// afterwards.
+///
+/// This is the entrypoint used by exports of modules compiled in dynamic
+/// mode.
+///
+/// # Safety
+///
+/// `code_ptr` and `method_ptr` must point to `code_len` and `method_len` bytes
+/// of UTF-8 in linear memory.
+#[export_name = "ruvy_invoke"]
+pub unsafe extern "C" fn invoke(
+    code_ptr: *const u8,
+    code_len: usize,
+    method_ptr: *const u8,
+    method_len: usize,
+) {
+    let _wasm_ctx = WasmCtx::new();
+
+    finish(
+        runtime::eval(str_from_raw_parts(code_ptr, code_len))
+            .and_then(|_| runtime::call(str_from_raw_parts(method_ptr, method_len))),
+    );
+}
+
+/// Allocates or resizes a region of linear memory so modules compiled in
+/// dynamic mode can write into this module's memory.
+///
+/// # Safety
+///
+/// `original_ptr` must be null or a pointer previously returned by this
+/// function with `original_size` and `alignment`.
+#[export_name = "canonical_abi_realloc"]
+pub unsafe extern "C" fn canonical_abi_realloc(
+    original_ptr: *mut u8,
+    original_size: usize,
+    alignment: usize,
+    new_size: usize,
+) -> *mut c_void {
+    assert!(new_size >= original_size);
+
+    // Zero-sized allocations are not permitted by the global allocator.
+    if new_size == 0 {
+        return ptr::without_provenance_mut(alignment);
+    }
 
-    if let Ok(preload_path) = env::var("RUVY_PRELOAD_PATH") {
-        runtime::preload_files(preload_path);
+    let new_mem = match original_size {
+        0 => alloc::alloc(alloc::Layout::from_size_align(new_size, alignment).unwrap()),
+        _ => alloc::realloc(
+            original_ptr,
+            alloc::Layout::from_size_align(original_size, alignment).unwrap(),
+            new_size,
+        ),
+    };
+    if new_mem.is_null() {
+        alloc::handle_alloc_error(alloc::Layout::from_size_align(new_size, alignment).unwrap());
     }
+    new_mem as *mut c_void
+}
+
+/// Cleans up the VM after running Ruby code, which runs `at_exit` handlers
+/// even if the code failed, then exits if either of them failed.
+fn finish<T>(result: Result<T>) {
+    let cleanup_result = cleanup_ruby();
+    exit_on_error(result.and(cleanup_result));
+}
+
+/// Exits through WASI if `result` is an error, so hosts see an exit code
+/// instead of a trap.
+///
+/// Calls to `exit` or `abort` in Ruby code exit with the status they were
+/// given. Other errors are printed on stderr and exit with a status of 1.
+fn exit_on_error<T>(result: Result<T>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => match err.downcast_ref::<Exit>() {
+            Some(Exit(status)) => process::exit(*status),
+            None => {
+                eprintln!("{err:#}");
+                process::exit(1);
+            }
+        },
+    }
+}
 
-    let contents = io::read_to_string(io::stdin()).unwrap();
-    USER_CODE.set(contents).unwrap();
+unsafe fn str_from_raw_parts<'a>(ptr: *const u8, len: usize) -> &'a str {
+    str::from_utf8(slice::from_raw_parts(ptr, len)).unwrap()
 }
 
 // RAII abstraction for calling Wasm ctors and dtors for exported non-main functions.
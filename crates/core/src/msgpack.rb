@@ -0,0 +1,30 @@
+# The parts of the `msgpack` gem scripts use, implemented by
+# `MessagePack::Native` in Rust, which also defines the exception classes.
+#
+# `msgpack` is marked as loaded, so `require "msgpack"` keeps working without
+# the gem.
+module MessagePack
+  class << self
+    def pack(obj, io = nil)
+      data = Native.pack(obj)
+      return data unless io
+
+      io.write(data)
+      io
+    end
+    alias_method :dump, :pack
+
+    def unpack(data)
+      Native.unpack(data.to_str)
+    end
+    alias_method :load, :unpack
+  end
+end
+
+class Object
+  def to_msgpack(io = nil)
+    MessagePack.pack(self, io)
+  end
+end
+
+$LOADED_FEATURES << "msgpack.rb"
@@ -0,0 +1,449 @@
+use std::os::raw::{c_char, c_long};
+
+use anyhow::{Error, Result};
+use ruvy_wasm_sys::{
+    rb_ary_entry, rb_ary_new_capa, rb_ary_push, rb_hash_aset, rb_hash_new, rb_utf8_str_new,
+    ruby_value_type_RUBY_T_ARRAY, ruby_value_type_RUBY_T_BIGNUM, ruby_value_type_RUBY_T_FALSE,
+    ruby_value_type_RUBY_T_FIXNUM, ruby_value_type_RUBY_T_FLOAT, ruby_value_type_RUBY_T_HASH,
+    ruby_value_type_RUBY_T_NIL, ruby_value_type_RUBY_T_STRING, ruby_value_type_RUBY_T_SYMBOL,
+    ruby_value_type_RUBY_T_TRUE, ruvy_rarray_len, ruvy_rstring_is_binary,
+};
+
+use crate::{
+    native::{self, function, Exception, Module},
+    runtime::{self, protect},
+    value::{Symbol, Value},
+};
+
+/// Ruby code defining the `MessagePack` methods on top of
+/// `MessagePack::Native`.
+const MSGPACK: &str = include_str!("msgpack.rb");
+
+/// Maximum depth of the arrays and maps packed and unpacked.
+const MAX_NESTING: usize = 128;
+
+/// Maximum length, marker and number of bytes of the length of a format of
+/// strings, binaries, arrays or maps, where no bytes means the length is part
+/// of the marker.
+type Format = (usize, u8, usize);
+
+const STR: &[Format] = &[
+    (31, 0xa0, 0),
+    (0xff, 0xd9, 1),
+    (0xffff, 0xda, 2),
+    (0xffff_ffff, 0xdb, 4),
+];
+const BIN: &[Format] = &[(0xff, 0xc4, 1), (0xffff, 0xc5, 2), (0xffff_ffff, 0xc6, 4)];
+const ARRAY: &[Format] = &[(15, 0x90, 0), (0xffff, 0xdc, 2), (0xffff_ffff, 0xdd, 4)];
+const MAP: &[Format] = &[(15, 0x80, 0), (0xffff, 0xde, 2), (0xffff_ffff, 0xdf, 4)];
+
+/// Defines the `MessagePack` module, which takes the place of the `msgpack`
+/// gem.
+pub fn define() -> Result<()> {
+    let msgpack = native::define_module("MessagePack")?;
+    let unpack_error =
+        msgpack.define_class("UnpackError", Some(native::lookup_module("StandardError")?))?;
+    for name in ["MalformedFormatError", "StackError", "UnknownExtTypeError"] {
+        msgpack.define_class(name, Some(unpack_error))?;
+    }
+    let native = msgpack.define_module("Native")?;
+    native.define_module_function("pack", function!(pack))?;
+    native.define_module_function("unpack", function!(unpack))?;
+    runtime::eval(MSGPACK)?;
+    Ok(())
+}
+
+/// Unpacks the MessagePack object in the binary string `data`.
+///
+/// Strings become `UTF-8` strings and binaries become binary strings, like
+/// the `msgpack` gem does. Extension types aren't supported.
+pub fn unpack(data: Value) -> Result<Value> {
+    let mut unpacker = Unpacker {
+        data: data.string_bytes()?,
+        position: 0,
+        depth: 0,
+    };
+    // The objects are only referenced from Rust until the data is unpacked.
+    runtime::without_gc(|| {
+        let value = unpacker.read()?;
+        let extra = unpacker.data.len() - unpacker.position;
+        if extra > 0 {
+            return Err(error(
+                "MessagePack::MalformedFormatError",
+                format!("{extra} extra bytes after the deserialized object"),
+            ));
+        }
+        Ok(value)
+    })
+}
+
+struct Unpacker<'a> {
+    data: &'a [u8],
+    position: usize,
+    /// Number of arrays and maps containing the object being read.
+    depth: usize,
+}
+
+impl<'a> Unpacker<'a> {
+    fn read(&mut self) -> Result<Value> {
+        let marker = self.read_bytes(1)?[0];
+        match marker {
+            0x00..=0x7f => Value::new(marker as i64),
+            0x80..=0x8f => self.read_map((marker & 0x0f) as usize),
+            0x90..=0x9f => self.read_array((marker & 0x0f) as usize),
+            0xa0..=0xbf => self.read_str((marker & 0x1f) as usize),
+            0xc0 => Ok(Value::NIL),
+            0xc2 => Ok(Value::FALSE),
+            0xc3 => Ok(Value::TRUE),
+            0xc4 => {
+                let len = self.read_len(1)?;
+                self.read_bin(len)
+            }
+            0xc5 => {
+                let len = self.read_len(2)?;
+                self.read_bin(len)
+            }
+            0xc6 => {
+                let len = self.read_len(4)?;
+                self.read_bin(len)
+            }
+            0xca => Value::new(f32::from_be_bytes(self.read_fixed()?) as f64),
+            0xcb => Value::new(f64::from_be_bytes(self.read_fixed()?)),
+            0xcc => Value::new(self.read_bytes(1)?[0] as u64),
+            0xcd => Value::new(u16::from_be_bytes(self.read_fixed()?) as u64),
+            0xce => Value::new(u32::from_be_bytes(self.read_fixed()?) as u64),
+            0xcf => Value::new(u64::from_be_bytes(self.read_fixed()?)),
+            0xd0 => Value::new(self.read_bytes(1)?[0] as i8 as i64),
+            0xd1 => Value::new(i16::from_be_bytes(self.read_fixed()?) as i64),
+            0xd2 => Value::new(i32::from_be_bytes(self.read_fixed()?) as i64),
+            0xd3 => Value::new(i64::from_be_bytes(self.read_fixed()?)),
+            0xd9 => {
+                let len = self.read_len(1)?;
+                self.read_str(len)
+            }
+            0xda => {
+                let len = self.read_len(2)?;
+                self.read_str(len)
+            }
+            0xdb => {
+                let len = self.read_len(4)?;
+                self.read_str(len)
+            }
+            0xdc => {
+                let len = self.read_len(2)?;
+                self.read_array(len)
+            }
+            0xdd => {
+                let len = self.read_len(4)?;
+                self.read_array(len)
+            }
+            0xde => {
+                let len = self.read_len(2)?;
+                self.read_map(len)
+            }
+            0xdf => {
+                let len = self.read_len(4)?;
+                self.read_map(len)
+            }
+            0xc7..=0xc9 | 0xd4..=0xd8 => Err(error(
+                "MessagePack::UnknownExtTypeError",
+                "extension types are not supported".to_string(),
+            )),
+            0xe0..=0xff => Value::new(marker as i8 as i64),
+            0xc1 => Err(error(
+                "MessagePack::MalformedFormatError",
+                format!("invalid byte {marker:#04x}"),
+            )),
+        }
+    }
+
+    fn read_array(&mut self, len: usize) -> Result<Value> {
+        self.nested(|unpacker| {
+            // The length is only trusted as far as the data could hold it.
+            let capacity = len.min(unpacker.remaining());
+            let array =
+                protect(|| unsafe { Value::from_raw(rb_ary_new_capa(capacity as c_long)) })?;
+            for _ in 0..len {
+                let element = unpacker.read()?;
+                unsafe { rb_ary_push(array.as_raw(), element.as_raw()) };
+            }
+            Ok(array)
+        })
+    }
+
+    fn read_map(&mut self, len: usize) -> Result<Value> {
+        self.nested(|unpacker| {
+            let hash = protect(|| unsafe { Value::from_raw(rb_hash_new()) })?;
+            for _ in 0..len {
+                let key = unpacker.read()?;
+                let value = unpacker.read()?;
+                unsafe { rb_hash_aset(hash.as_raw(), key.as_raw(), value.as_raw()) };
+            }
+            Ok(hash)
+        })
+    }
+
+    fn nested(&mut self, f: impl FnOnce(&mut Self) -> Result<Value>) -> Result<Value> {
+        if self.depth == MAX_NESTING {
+            return Err(error(
+                "MessagePack::StackError",
+                format!("nesting of {} is too deep", MAX_NESTING + 1),
+            ));
+        }
+        self.depth += 1;
+        let value = f(self)?;
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    /// Reads a string, which is UTF-8 without being checked, like the
+    /// `msgpack` gem does.
+    fn read_str(&mut self, len: usize) -> Result<Value> {
+        let bytes = self.read_bytes(len)?;
+        protect(|| unsafe {
+            Value::from_raw(rb_utf8_str_new(
+                bytes.as_ptr() as *const c_char,
+                bytes.len() as c_long,
+            ))
+        })
+    }
+
+    fn read_bin(&mut self, len: usize) -> Result<Value> {
+        Value::new(self.read_bytes(len)?)
+    }
+
+    /// Reads the big-endian length of a string, binary, array or map.
+    fn read_len(&mut self, size: usize) -> Result<usize> {
+        Ok(self
+            .read_bytes(size)?
+            .iter()
+            .fold(0, |len, byte| len << 8 | *byte as usize))
+    }
+
+    fn read_fixed<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.remaining() {
+            return Err(error(
+                "MessagePack::MalformedFormatError",
+                "unexpected end of data".to_string(),
+            ));
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+}
+
+/// Packs `value` into a binary string.
+///
+/// Symbols are packed as strings, and binary strings as binaries, like the
+/// `msgpack` gem does. Other objects are packed with their own `to_msgpack`
+/// method.
+pub fn pack(value: Value) -> Result<Value> {
+    let mut packer = Packer {
+        data: vec![],
+        depth: 0,
+    };
+    packer.write(value)?;
+    Value::new(packer.data.as_slice())
+}
+
+struct Packer {
+    data: Vec<u8>,
+    /// Number of arrays and maps containing the object being written.
+    depth: usize,
+}
+
+impl Packer {
+    #[allow(non_upper_case_globals)]
+    fn write(&mut self, value: Value) -> Result<()> {
+        match value.ruby_type() {
+            ruby_value_type_RUBY_T_NIL => self.data.push(0xc0),
+            ruby_value_type_RUBY_T_FALSE => self.data.push(0xc2),
+            ruby_value_type_RUBY_T_TRUE => self.data.push(0xc3),
+            ruby_value_type_RUBY_T_FIXNUM | ruby_value_type_RUBY_T_BIGNUM => {
+                self.write_integer(value)?
+            }
+            ruby_value_type_RUBY_T_FLOAT => {
+                self.data.push(0xcb);
+                self.data
+                    .extend_from_slice(&value.to::<f64>()?.to_be_bytes());
+            }
+            ruby_value_type_RUBY_T_STRING => {
+                let bytes = value.string_bytes()?;
+                let binary = unsafe { ruvy_rstring_is_binary(value.as_raw()) } != 0;
+                self.write_header(bytes.len(), if binary { BIN } else { STR })?;
+                self.data.extend_from_slice(bytes);
+            }
+            ruby_value_type_RUBY_T_SYMBOL => {
+                let name = value.to::<Symbol>()?.0;
+                self.write_header(name.len(), STR)?;
+                self.data.extend_from_slice(name.as_bytes());
+            }
+            ruby_value_type_RUBY_T_ARRAY => {
+                let len = unsafe { ruvy_rarray_len(value.as_raw()) };
+                self.write_header(len as usize, ARRAY)?;
+                self.nested(|packer| {
+                    for index in 0..len {
+                        let element =
+                            unsafe { Value::from_raw(rb_ary_entry(value.as_raw(), index)) };
+                        packer.write(element)?;
+                    }
+                    Ok(())
+                })?;
+            }
+            ruby_value_type_RUBY_T_HASH => {
+                let pairs = value.pairs()?;
+                self.write_header(pairs.len(), MAP)?;
+                self.nested(|packer| {
+                    for (key, value) in pairs {
+                        packer.write(key)?;
+                        packer.write(value)?;
+                    }
+                    Ok(())
+                })?;
+            }
+            _ if has_own_to_msgpack(value)? => {
+                let data = value.call("to_msgpack", &[])?;
+                self.data.extend_from_slice(data.string_bytes()?);
+            }
+            _ => {
+                return Err(Exception::type_error(format!(
+                    "can't pack {} as MessagePack",
+                    value.class_name()
+                ))
+                .into())
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes an integer in the smallest format that holds it.
+    fn write_integer(&mut self, value: Value) -> Result<()> {
+        let Ok(int) = value.to::<i64>() else {
+            let Ok(int) = value.to::<u64>() else {
+                return Err(Exception::range_error(format!(
+                    "{} is too big to pack as MessagePack",
+                    value.inspect()?
+                ))
+                .into());
+            };
+            self.data.push(0xcf);
+            self.data.extend_from_slice(&int.to_be_bytes());
+            return Ok(());
+        };
+        match int {
+            0..=0x7f => self.data.push(int as u8),
+            -32..=-1 => self.data.push(int as i8 as u8),
+            0x80..=0xff => self.data.extend_from_slice(&[0xcc, int as u8]),
+            0x100..=0xffff => {
+                self.data.push(0xcd);
+                self.data.extend_from_slice(&(int as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.data.push(0xce);
+                self.data.extend_from_slice(&(int as u32).to_be_bytes());
+            }
+            0x1_0000_0000.. => {
+                self.data.push(0xcf);
+                self.data.extend_from_slice(&(int as u64).to_be_bytes());
+            }
+            -0x80..=-33 => self.data.extend_from_slice(&[0xd0, int as i8 as u8]),
+            -0x8000..=-0x81 => {
+                self.data.push(0xd1);
+                self.data.extend_from_slice(&(int as i16).to_be_bytes());
+            }
+            -0x8000_0000..=-0x8001 => {
+                self.data.push(0xd2);
+                self.data.extend_from_slice(&(int as i32).to_be_bytes());
+            }
+            _ => {
+                self.data.push(0xd3);
+                self.data.extend_from_slice(&int.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the marker and length of a string, binary, array or map in the
+    /// first of `formats` that fits `len`.
+    fn write_header(&mut self, len: usize, formats: &[Format]) -> Result<()> {
+        let Some(&(_, marker, size)) = formats.iter().find(|(max, _, _)| len <= *max) else {
+            return Err(Exception::range_error(format!(
+                "{len} is too long to pack as MessagePack"
+            ))
+            .into());
+        };
+        if size == 0 {
+            self.data.push(marker | len as u8);
+        } else {
+            self.data.push(marker);
+            self.data
+                .extend_from_slice(&(len as u32).to_be_bytes()[4 - size..]);
+        }
+        Ok(())
+    }
+
+    fn nested(&mut self, f: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        if self.depth == MAX_NESTING {
+            return Err(Exception::argument_error(format!(
+                "nesting of {} is too deep",
+                MAX_NESTING + 1
+            ))
+            .into());
+        }
+        self.depth += 1;
+        f(self)?;
+        self.depth -= 1;
+        Ok(())
+    }
+}
+
+/// Whether `value` has a `to_msgpack` method other than the generic one of
+/// `Object`, which packs objects of the types `MessagePack` supports.
+fn has_own_to_msgpack(value: Value) -> Result<bool> {
+    let method = value.call("method", &[Value::new(Symbol("to_msgpack".to_string()))?])?;
+    Ok(method.call("owner", &[])? != Module::object().as_value())
+}
+
+/// Returns an error raising the exception `class` defined by [`define`].
+fn error(class: &str, message: String) -> Error {
+    match native::lookup_module(class) {
+        Ok(class) => Exception::new(class, message).into(),
+        Err(err) => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{eval, init_ruby};
+
+    #[test]
+    fn test_pack_unpack() -> Result<()> {
+        init_ruby();
+        define()?;
+        assert_eq!(
+            vec![0x82, 0xa1, b'a', 0xff, 0xa1, b'b', 0xcd, 0x01, 0x00],
+            eval("MessagePack.pack({ 'a' => -1, b: 256 })")?.string_bytes()?
+        );
+        assert!(eval(
+            r#"value = { "a" => [1, -33, 2**40, 2**64 - 1, 1.5, nil, true, "é" * 40, "\xFF".b] }
+            MessagePack.unpack(MessagePack.pack(value)) == value"#
+        )?
+        .is_truthy());
+        assert_eq!(
+            "MessagePack::MalformedFormatError",
+            eval(r#"begin; MessagePack.unpack("\x92\x01"); rescue => e; e.class.name; end"#)?
+                .to::<String>()?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    ffi::CString,
+    fmt,
+    os::raw::{c_char, c_int, c_long},
+    slice,
+};
+
+use anyhow::{Context, Error, Result};
+#[cfg(any(feature = "msgpack", test))]
+use ruvy_wasm_sys::rb_eRangeError;
+#[cfg(any(feature = "json", feature = "msgpack"))]
+use ruvy_wasm_sys::{rb_cObject, rb_define_class_under, rb_path2class};
+use ruvy_wasm_sys::{
+    rb_define_module, rb_define_module_function, rb_define_module_under, rb_eArgError,
+    rb_eRuntimeError, rb_eSystemExit, rb_eTypeError, rb_exc_new, rb_exc_raise, rb_funcallv,
+    rb_int2inum, rb_intern, VALUE,
+};
+
+use crate::{
+    runtime::{protect, Exit, RubyError},
+    value::{FromValue, IntoValue, Value},
+};
+
+/// A Ruby module or class.
+///
+/// Modules and classes are assigned to constants, so they're never garbage
+/// collected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Module(Value);
+
+/// Defines the top-level module `name`, or returns it if it exists.
+pub fn define_module(name: &str) -> Result<Module> {
+    let name = c_string(name)?;
+    protect(|| Module(unsafe { Value::from_raw(rb_define_module(name.as_ptr())) }))
+}
+
+/// Returns the module or class at `path`, e.g. `Foo::Bar`.
+#[cfg(any(feature = "json", feature = "msgpack"))]
+pub fn lookup_module(path: &str) -> Result<Module> {
+    let path = c_string(path)?;
+    protect(|| unsafe { Module::from_raw(rb_path2class(path.as_ptr())) })
+}
+
+impl Module {
+    #[cfg(any(feature = "json", feature = "msgpack"))]
+    pub fn object() -> Self {
+        unsafe { Self::from_raw(rb_cObject) }
+    }
+
+    /// # Safety
+    ///
+    /// `raw` must be a Ruby module or class.
+    pub unsafe fn from_raw(raw: VALUE) -> Self {
+        Self(Value::from_raw(raw))
+    }
+
+    #[cfg(any(feature = "json", feature = "msgpack"))]
+    pub fn as_value(self) -> Value {
+        self.0
+    }
+
+    fn as_raw(self) -> VALUE {
+        self.0.as_raw()
+    }
+
+    /// Defines the module `name` nested in this one, see [`define_module`].
+    pub fn define_module(self, name: &str) -> Result<Module> {
+        let name = c_string(name)?;
+        protect(|| unsafe {
+            Module(Value::from_raw(rb_define_module_under(
+                self.as_raw(),
+                name.as_ptr(),
+            )))
+        })
+    }
+
+    /// Defines the class `name` nested in this module, inheriting from
+    /// `superclass`, or `Object` if it's `None`, or returns it if it exists.
+    #[cfg(any(feature = "json", feature = "msgpack"))]
+    pub fn define_class(self, name: &str, superclass: Option<Module>) -> Result<Module> {
+        let name = c_string(name)?;
+        let superclass = superclass.unwrap_or_else(Module::object);
+        protect(|| unsafe {
+            Module(Value::from_raw(rb_define_class_under(
+                self.as_raw(),
+                name.as_ptr(),
+                superclass.as_raw(),
+            )))
+        })
+    }
+
+    /// Defines the method `name` both on the module itself and as a private
+    /// instance method, like `module_function` does.
+    pub fn define_module_function(self, name: &str, method: Method) -> Result<()> {
+        let name = c_string(name)?;
+        protect(|| unsafe {
+            // Methods taking any number of arguments, with an arity of -1,
+            // are called with the arguments as a C array.
+            let func = std::mem::transmute::<CFunc, unsafe extern "C" fn() -> VALUE>(method.0);
+            rb_define_module_function(self.as_raw(), name.as_ptr(), Some(func), -1)
+        })
+    }
+}
+
+fn c_string(name: &str) -> Result<CString> {
+    CString::new(name).with_context(|| format!("Invalid name {name:?}"))
+}
+
+/// The C function Ruby calls for a method taking any number of arguments.
+type CFunc = unsafe extern "C" fn(c_int, *const VALUE, VALUE) -> VALUE;
+
+/// A method implemented in Rust, created with [`function!`].
+#[derive(Clone, Copy)]
+pub struct Method(CFunc);
+
+impl Method {
+    #[doc(hidden)]
+    pub fn new(func: CFunc) -> Self {
+        Self(func)
+    }
+}
+
+/// Creates a [`Method`] calling the Rust function `$f` with the method's
+/// arguments, ignoring the receiver.
+///
+/// The arguments are converted with [`FromValue`] and the value returned with
+/// [`IntoValue`]. `$f` returns a `Result`, whose errors are raised as
+/// exceptions, see [`Exception`].
+macro_rules! function {
+    ($f:expr) => {{
+        unsafe extern "C" fn call(
+            argc: ::std::os::raw::c_int,
+            argv: *const ::ruvy_wasm_sys::VALUE,
+            _receiver: ::ruvy_wasm_sys::VALUE,
+        ) -> ::ruvy_wasm_sys::VALUE {
+            $crate::native::invoke(&$f, argc, argv)
+        }
+        $crate::native::Method::new(call)
+    }};
+}
+
+pub(crate) use function;
+
+/// A Rust function that can implement a method, taking arguments of the types
+/// in the tuple `Args`.
+pub trait Function<Args> {
+    const ARITY: usize;
+
+    /// Calls the function with exactly [`Function::ARITY`] arguments.
+    fn call(&self, args: &[Value]) -> Result<Value>;
+}
+
+macro_rules! one {
+    ($arg:ident) => {
+        1
+    };
+}
+
+macro_rules! impl_function {
+    ($($arg:ident),*) => {
+        impl<Func, Ret, $($arg),*> Function<($($arg,)*)> for Func
+        where
+            Func: Fn($($arg),*) -> Result<Ret>,
+            Ret: IntoValue,
+            $($arg: FromValue,)*
+        {
+            const ARITY: usize = 0 $(+ one!($arg))*;
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn call(&self, args: &[Value]) -> Result<Value> {
+                let mut args = args.iter().copied();
+                $(let $arg = <$arg>::from_value(args.next().unwrap()).map_err(conversion_error)?;)*
+                self($($arg),*)?.into_value()
+            }
+        }
+    };
+}
+
+impl_function!();
+impl_function!(A);
+impl_function!(A, B);
+impl_function!(A, B, C);
+impl_function!(A, B, C, D);
+impl_function!(A, B, C, D, E);
+impl_function!(A, B, C, D, E, F);
+impl_function!(A, B, C, D, E, F, G);
+impl_function!(A, B, C, D, E, F, G, H);
+
+/// Raises a `TypeError` for arguments that couldn't be converted, unless the
+/// conversion itself raised.
+fn conversion_error(err: Error) -> Error {
+    if err.is::<RubyError>() {
+        return err;
+    }
+    Exception::type_error(format!("{err:#}")).into()
+}
+
+/// Calls `f` with the arguments a method was called with, raising any error
+/// it returns.
+///
+/// # Safety
+///
+/// `argv` must point to `argc` Ruby objects.
+#[doc(hidden)]
+pub unsafe fn invoke<F: Function<Args>, Args>(f: &F, argc: c_int, argv: *const VALUE) -> VALUE {
+    // Raising doesn't unwind the stack, so everything owned by this call needs
+    // to be dropped before.
+    let exception = match call(f, argc, argv) {
+        Ok(value) => return value.as_raw(),
+        Err(err) => to_exception(err),
+    };
+    rb_exc_raise(exception)
+}
+
+unsafe fn call<F: Function<Args>, Args>(f: &F, argc: c_int, argv: *const VALUE) -> Result<Value> {
+    let args = if argc == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(argv as *const Value, argc as usize)
+    };
+    if args.len() != F::ARITY {
+        return Err(Exception::argument_error(format!(
+            "wrong number of arguments (given {}, expected {})",
+            args.len(),
+            F::ARITY
+        ))
+        .into());
+    }
+    f.call(args)
+}
+
+/// Converts an error returned by a method into the exception to raise.
+///
+/// Exceptions raised by Ruby code the method called are raised again, and
+/// errors other than [`Exception`] are raised as `RuntimeError`s.
+fn to_exception(err: Error) -> VALUE {
+    if let Some(exception) = err.downcast_ref::<Exception>() {
+        return exception.to_raw();
+    }
+    if let Some(error) = err.downcast_ref::<RubyError>() {
+        return error.exception().as_raw();
+    }
+    if let Some(Exit(status)) = err.downcast_ref::<Exit>() {
+        unsafe {
+            let status = rb_int2inum(*status as isize);
+            return rb_funcallv(rb_eSystemExit, rb_intern(c"new".as_ptr()), 1, &status);
+        }
+    }
+    Exception::runtime_error(format!("{err:#}")).to_raw()
+}
+
+/// An exception for a method implemented in Rust to raise, by returning it as
+/// an error.
+#[derive(Debug)]
+pub struct Exception {
+    class: Module,
+    message: String,
+}
+
+impl Exception {
+    pub fn new(class: Module, message: impl Into<String>) -> Self {
+        Self {
+            class,
+            message: message.into(),
+        }
+    }
+
+    pub fn argument_error(message: impl Into<String>) -> Self {
+        Self::new(unsafe { Module::from_raw(rb_eArgError) }, message)
+    }
+
+    pub fn type_error(message: impl Into<String>) -> Self {
+        Self::new(unsafe { Module::from_raw(rb_eTypeError) }, message)
+    }
+
+    #[cfg(any(feature = "msgpack", test))]
+    pub fn range_error(message: impl Into<String>) -> Self {
+        Self::new(unsafe { Module::from_raw(rb_eRangeError) }, message)
+    }
+
+    pub fn runtime_error(message: impl Into<String>) -> Self {
+        Self::new(unsafe { Module::from_raw(rb_eRuntimeError) }, message)
+    }
+
+    fn to_raw(&self) -> VALUE {
+        unsafe {
+            rb_exc_new(
+                self.class.as_raw(),
+                self.message.as_ptr() as *const c_char,
+                self.message.len() as c_long,
+            )
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Exception {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{eval, init_ruby};
+
+    fn add(a: i64, b: i64) -> Result<i64> {
+        a.checked_add(b)
+            .ok_or_else(|| Exception::range_error("Integer overflow").into())
+    }
+
+    #[test]
+    fn test_define_module_function() -> Result<()> {
+        init_ruby();
+        let module = define_module("Native")?;
+        module.define_module_function("add", function!(add))?;
+        assert_eq!(3, eval("Native.add(1, 2)")?.to::<i64>()?);
+        assert_eq!(
+            "TypeError: Expected Integer, got String",
+            eval("begin; Native.add(1, '2'); rescue => e; \"#{e.class}: #{e.message}\"; end")?
+                .to::<String>()?
+        );
+        assert_eq!(
+            "ArgumentError",
+            eval("begin; Native.add(1); rescue => e; e.class.name; end")?.to::<String>()?
+        );
+        Ok(())
+    }
+}
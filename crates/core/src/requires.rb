@@ -0,0 +1,57 @@
+# Finds the features Ruby code loads with `require` and `require_relative`
+# calls taking a string literal, by walking the code's syntax tree.
+#
+# Calls with any other argument, like `require File.join(dir, "helper")`,
+# can't be resolved before the code runs and are skipped.
+module RuvyRequires
+  CALLS = %i[require require_relative].freeze
+
+  class << self
+    # Returns the features required by the top-level statements of `code`,
+    # loaded from `path`, followed by those required anywhere else, like in
+    # conditionals, methods and blocks. `require_relative` features are
+    # expanded from the directory of `path`.
+    #
+    # Returns no features if `code` has syntax errors.
+    def find(code, path)
+      root = begin
+        RubyVM::AbstractSyntaxTree.parse(code)
+      rescue SyntaxError
+        # Compiling the code reports the error along with the code's path.
+        return [[], []]
+      end
+      body = root.children.last
+      statements = body&.type == :BLOCK ? body.children : [body]
+      top_level = statements.filter_map { |node| feature(node, path) }.uniq
+      nested = []
+      each_node(root) { |node| nested << feature(node, path) }
+      [top_level, nested.compact.uniq - top_level]
+    end
+
+    private
+
+    def each_node(node, &block)
+      return unless node.is_a?(RubyVM::AbstractSyntaxTree::Node)
+
+      yield node
+      node.children.each { |child| each_node(child, &block) }
+    end
+
+    def feature(node, path)
+      # Calls with an explicit receiver, like `Kernel.require`, are `CALL`s.
+      return unless node&.type == :FCALL
+
+      method, args = node.children
+      return unless CALLS.include?(method) && args&.type == :LIST
+
+      # The list of arguments ends with `nil`.
+      argument, rest = args.children
+      return unless argument&.type == :STR && rest.nil?
+
+      feature = argument.children.first
+      return feature if method == :require
+
+      File.expand_path(feature, File.dirname(path))
+    end
+  end
+end
@@ -0,0 +1,109 @@
+use anyhow::Result;
+
+use crate::runtime;
+
+/// Ruby code finding the features a file requires in its syntax tree.
+const FIND: &str = include_str!("requires.rb");
+
+/// Guest directories the host mounts the user's own files in, as opposed to
+/// the standard library and gems.
+const USER_DIRS: [&str; 3] = ["/ruvy/input", "/ruvy/load-path", "/ruvy/preload"];
+
+/// The features a Ruby file loads with `require` and `require_relative` calls
+/// taking a string literal.
+#[derive(Debug, PartialEq)]
+pub struct Requires {
+    /// Features required by the file's top-level statements, which are loaded
+    /// whenever the file is.
+    pub top_level: Vec<String>,
+    /// Features required anywhere else, e.g. in conditionals or methods, which
+    /// may never be loaded, like optional dependencies rescuing `LoadError`.
+    pub nested: Vec<String>,
+}
+
+/// Defines the module used by [`find`] until [`remove`] is called.
+pub fn define() -> Result<()> {
+    runtime::eval(FIND)?;
+    Ok(())
+}
+
+/// Finds the features `code`, loaded from `path`, requires, so they can be
+/// loaded before the code runs.
+///
+/// Calls whose argument is not a plain string literal can't be resolved ahead
+/// of time and are skipped.
+pub fn find(code: &str, path: &str) -> Result<Requires> {
+    let mut features = runtime::call_module_method(c"RuvyRequires", c"find", &[code, path])?
+        .to::<Vec<Vec<String>>>()?;
+    let nested = features.pop().unwrap_or_default();
+    let top_level = features.pop().unwrap_or_default();
+    Ok(Requires { top_level, nested })
+}
+
+/// Removes the module defined by [`define`], so it isn't part of the snapshot.
+pub fn remove() -> Result<()> {
+    runtime::eval("Object.send(:remove_const, :RuvyRequires)")?;
+    Ok(())
+}
+
+/// Whether the file at the guest path `path` is part of the user's code,
+/// whose requires are searched too.
+pub fn is_user_file(path: &str) -> bool {
+    USER_DIRS.iter().any(|dir| {
+        path.strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/') || rest.starts_with('-'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() -> Result<()> {
+        runtime::init_ruby();
+        define()?;
+        let code = r##"
+require "json"
+require('set')
+  require_relative "lib/helper"
+require "#{name}"
+require "set"
+required = true
+require_relative_thing
+text = <<~RUBY
+  require "heredoc"
+RUBY
+=begin
+require "comment"
+=end
+begin
+  require "optional"
+rescue LoadError
+end
+def run
+  require "lazy"
+end
+"##;
+        assert_eq!(
+            Requires {
+                top_level: vec![
+                    "json".to_string(),
+                    "set".to_string(),
+                    "/app/lib/helper".to_string(),
+                ],
+                nested: vec!["optional".to_string(), "lazy".to_string()],
+            },
+            find(code, "/app/main.rb")?
+        );
+        remove()
+    }
+
+    #[test]
+    fn test_is_user_file() {
+        assert!(is_user_file("/ruvy/input/lib/helper.rb"));
+        assert!(is_user_file("/ruvy/load-path-2/helper.rb"));
+        assert!(!is_user_file("/ruvy/stdlib/set.rb"));
+        assert!(!is_user_file("/ruvy/inputs.rb"));
+    }
+}
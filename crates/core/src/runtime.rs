@@ -1,8 +1,66 @@
-use std::fs;
+use std::{fmt, fs, path::Path};
 
-use anyhow::{anyhow, bail, Result};
-use ruvy_wasm_sys::{rb_eval_string_protect, ruby_init, ruby_init_loadpath, VALUE};
-use std::{ffi::CString, os::raw::c_char};
+use anyhow::{anyhow, Context, Result};
+use ruvy_wasm_sys::{
+    rb_ary_unshift, rb_cObject, rb_const_get, rb_eLoadError, rb_eSystemExit, rb_errinfo,
+    rb_eval_string_protect, rb_funcallv, rb_funcallv_kw, rb_gc_disable, rb_gc_enable,
+    rb_gc_register_mark_object, rb_gv_get, rb_hash_aset, rb_hash_new, rb_id2sym, rb_int2inum,
+    rb_intern, rb_mKernel, rb_num2int, rb_obj_is_kind_of, rb_path2class, rb_protect, rb_require,
+    rb_set_errinfo, rb_str_new, ruby_init, ruby_init_loadpath, ruby_set_argv,
+    ruby_special_consts_RUBY_Qfalse, ruby_special_consts_RUBY_Qnil, ruby_special_consts_RUBY_Qtrue,
+    ruvy_rstring_len, ruvy_rstring_ptr, RB_PASS_KEYWORDS, VALUE,
+};
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_long},
+    ptr, slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::value::{Handle, Value};
+
+const QNIL: VALUE = ruby_special_consts_RUBY_Qnil as VALUE;
+const QFALSE: VALUE = ruby_special_consts_RUBY_Qfalse as VALUE;
+
+/// Whether [`cleanup_ruby`] tore down the VM.
+static CLEANED_UP: AtomicBool = AtomicBool::new(false);
+
+/// The error returned when Ruby code calls `exit` or `abort`, or when cleaning
+/// up the VM results in a non-zero exit status.
+#[derive(Debug)]
+pub struct Exit(pub i32);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ruby exited with status {}", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+/// The error returned when Ruby code raises an exception, which it keeps so
+/// the exception can be raised again, see [`crate::native`].
+#[derive(Debug)]
+pub struct RubyError {
+    /// The exception's location, message, class and backtrace, formatted the
+    /// same way CRuby does.
+    report: String,
+    exception: Handle,
+}
+
+impl RubyError {
+    pub fn exception(&self) -> Value {
+        self.exception.get()
+    }
+}
+
+impl fmt::Display for RubyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.report)
+    }
+}
+
+impl std::error::Error for RubyError {}
 
 pub fn init_ruby() {
     unsafe {
@@ -11,37 +69,262 @@ pub fn init_ruby() {
     }
 }
 
-pub fn eval(code: &str) -> Result<VALUE> {
+pub fn eval(code: &str) -> Result<Value> {
     let c_code = CString::new(code)?;
     let mut state: i32 = 0;
     let result =
         unsafe { rb_eval_string_protect(c_code.as_ptr() as *const c_char, &mut state as *mut i32) };
 
     if state == 0 {
-        Ok(result)
+        Ok(unsafe { Value::from_raw(result) })
     } else {
-        Err(anyhow!("Error evaluating Ruby code. State: {}", state))
+        Err(take_exception(state))
+    }
+}
+
+/// Evaluates `code` at the top level as if it was loaded from `path`, so
+/// backtraces and `require_relative` calls refer to `path`.
+pub fn eval_file(code: &str, path: &str) -> Result<Value> {
+    protect_value(|| unsafe {
+        let binding = rb_const_get(rb_cObject, rb_intern(c"TOPLEVEL_BINDING".as_ptr()));
+        let args = [new_string(code), binding, new_string(path), rb_int2inum(1)];
+        rb_funcallv(
+            rb_mKernel,
+            rb_intern(c"eval".as_ptr()),
+            args.len() as c_int,
+            args.as_ptr(),
+        )
+    })
+}
+
+/// Compiles `code` to a `RubyVM::InstructionSequence` as if it was loaded
+/// from `path`, raising syntax errors without running any of the code.
+///
+/// The instruction sequence is never garbage collected, so it can be
+/// evaluated with [`eval_iseq`] from the snapshot.
+pub fn compile(code: &str, path: &str) -> Result<Value> {
+    let iseq = protect_value(|| unsafe {
+        let iseq_class = rb_path2class(c"RubyVM::InstructionSequence".as_ptr());
+        let args = [
+            new_string(code),
+            new_string(path),
+            new_string(path),
+            rb_int2inum(1),
+        ];
+        rb_funcallv(
+            iseq_class,
+            rb_intern(c"compile".as_ptr()),
+            args.len() as c_int,
+            args.as_ptr(),
+        )
+    })?;
+    unsafe { rb_gc_register_mark_object(iseq.as_raw()) };
+    Ok(iseq)
+}
+
+/// Evaluates an instruction sequence returned by [`compile`] at the top level.
+pub fn eval_iseq(iseq: Value) -> Result<Value> {
+    iseq.call("eval", &[])
+}
+
+/// Loads `feature` like `require` does.
+pub fn require(feature: &str) -> Result<()> {
+    let feature = CString::new(feature)?;
+    protect(|| unsafe { rb_require(feature.as_ptr()) })?;
+    Ok(())
+}
+
+/// Returns the paths of the files loaded with `require`.
+pub fn loaded_features() -> Result<Vec<String>> {
+    unsafe { Value::from_raw(rb_gv_get(c"$LOADED_FEATURES".as_ptr())) }.to()
+}
+
+/// Whether `err` is a `LoadError`, e.g. raised by `require` when the feature
+/// can't be found.
+pub fn is_load_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<RubyError>().is_some_and(|err| unsafe {
+        rb_obj_is_kind_of(err.exception().as_raw(), rb_eLoadError) != QFALSE
+    })
+}
+
+/// Prepends `paths` to `$LOAD_PATH`, keeping their order.
+pub fn add_load_paths<'a>(paths: impl DoubleEndedIterator<Item = &'a str>) -> Result<()> {
+    let load_path = unsafe { rb_gv_get(c"$LOAD_PATH".as_ptr()) };
+    for path in paths.rev() {
+        protect(|| unsafe { rb_ary_unshift(load_path, new_string(path)) })?;
+    }
+    Ok(())
+}
+
+/// Sets `ARGV` to `args`.
+pub fn set_argv(args: impl Iterator<Item = String>) -> Result<()> {
+    let args = args.map(CString::new).collect::<Result<Vec<_>, _>>()?;
+    let mut argv: Vec<_> = args.iter().map(|arg| arg.as_ptr() as *mut c_char).collect();
+    unsafe { ruby_set_argv(argv.len() as c_int, argv.as_mut_ptr()) };
+    Ok(())
+}
+
+/// Calls the top-level method named `name` without any arguments.
+pub fn call(name: &str) -> Result<Value> {
+    let name = CString::new(name)?;
+    protect_value(|| unsafe {
+        // Top-level methods are private methods of `main`, the top-level
+        // `self`, which `rb_funcallv` can call.
+        let binding = rb_const_get(rb_cObject, rb_intern(c"TOPLEVEL_BINDING".as_ptr()));
+        let main = rb_funcallv(binding, rb_intern(c"receiver".as_ptr()), 0, ptr::null());
+        rb_funcallv(main, rb_intern(name.as_ptr()), 0, ptr::null())
+    })
+}
+
+/// Calls the singleton method `method` of the class or module at `path`, e.g.
+/// `Foo::Bar`, with `args` as strings.
+pub fn call_module_method(path: &CStr, method: &CStr, args: &[&str]) -> Result<Value> {
+    let module = protect_value(|| unsafe { rb_path2class(path.as_ptr()) })?;
+    let method = protect(|| unsafe { rb_intern(method.as_ptr()) })?;
+    // The arguments are only referenced from Rust until the method is called.
+    let args = without_gc(|| {
+        args.iter()
+            .map(|arg| Value::new(*arg))
+            .collect::<Result<Vec<_>>>()
+    })?;
+    protect_value(|| unsafe {
+        // `Value` is `repr(transparent)`, so the arguments are `VALUE`s.
+        rb_funcallv(
+            module.as_raw(),
+            method,
+            args.len() as c_int,
+            args.as_ptr() as *const VALUE,
+        )
+    })
+}
+
+/// Calls `f` with the garbage collector disabled, so the objects it creates
+/// are kept even if they're only referenced from Rust, e.g. while building a
+/// large object graph.
+pub fn without_gc<T>(f: impl FnOnce() -> T) -> T {
+    let was_disabled = unsafe { rb_gc_disable() } == ruby_special_consts_RUBY_Qtrue as VALUE;
+    let result = f();
+    if !was_disabled {
+        unsafe { rb_gc_enable() };
+    }
+    result
+}
+
+/// Evaluates a Ruby file to preload, see [`ruvy_preload::files`].
+pub fn preload_file(file: &Path) -> Result<()> {
+    let prelude_contents =
+        fs::read_to_string(file).with_context(|| format!("Could not read {}", file.display()))?;
+    eval_file(&prelude_contents, &file.to_string_lossy())?;
+    Ok(())
+}
+
+/// Calls `f`, converting any Ruby exception it raises into an error.
+pub(crate) fn protect<T, F: FnOnce() -> T>(f: F) -> Result<T> {
+    unsafe extern "C" fn call<T, F: FnOnce() -> T>(data: VALUE) -> VALUE {
+        let (f, result) = &mut *(data as *mut (Option<F>, Option<T>));
+        *result = Some(f.take().unwrap()());
+        QNIL
+    }
+
+    let mut data: (Option<F>, Option<T>) = (Some(f), None);
+    let mut state = 0;
+    unsafe {
+        rb_protect(
+            Some(call::<T, F>),
+            &mut data as *mut (Option<F>, Option<T>) as VALUE,
+            &mut state,
+        )
+    };
+    match data.1 {
+        Some(result) if state == 0 => Ok(result),
+        _ => Err(take_exception(state)),
     }
 }
 
-pub fn preload_files(path: String) {
-    let entries = fs::read_dir(path).unwrap();
+/// Like [`protect`], for calls to the C API returning an object.
+fn protect_value<F: FnOnce() -> VALUE>(f: F) -> Result<Value> {
+    protect(|| unsafe { Value::from_raw(f()) })
+}
+
+/// Clears the pending exception and converts it into an error, see
+/// [`RubyError`].
+fn take_exception(state: i32) -> anyhow::Error {
+    let exception = unsafe { rb_errinfo() };
+    unsafe { rb_set_errinfo(QNIL) };
+    if exception == QNIL {
+        return anyhow!("Error evaluating Ruby code. State: {state}");
+    }
+    if unsafe { rb_obj_is_kind_of(exception, rb_eSystemExit) } != QFALSE {
+        let status = unsafe { rb_num2int(call_method(exception, c"status")) };
+        return Exit(status as i32).into();
+    }
+
+    let mut format_state = 0;
+    // The exception is only referenced from Rust, since it was cleared.
+    let report = without_gc(|| unsafe {
+        let report = rb_protect(Some(format_exception), exception, &mut format_state);
+        (format_state == 0).then(|| rstring_to_string(report))
+    });
+    let Some(report) = report else {
+        unsafe { rb_set_errinfo(QNIL) };
+        return anyhow!("Error formatting Ruby exception. State: {format_state}");
+    };
+    RubyError {
+        report: report.trim_end().to_string(),
+        exception: Handle::new(unsafe { Value::from_raw(exception) }),
+    }
+    .into()
+}
+
+/// Formats `exception` the way CRuby reports exceptions that aren't rescued.
+///
+/// The exception's `message` and `backtrace` methods can raise, which skips
+/// the rest of this function, so it only holds Ruby objects.
+unsafe extern "C" fn format_exception(exception: VALUE) -> VALUE {
+    let options = rb_hash_new();
+    rb_hash_aset(options, symbol(c"highlight"), QFALSE);
+    rb_hash_aset(options, symbol(c"order"), symbol(c"top"));
+    rb_funcallv_kw(
+        exception,
+        rb_intern(c"full_message".as_ptr()),
+        1,
+        &options,
+        RB_PASS_KEYWORDS as c_int,
+    )
+}
+
+unsafe fn symbol(name: &CStr) -> VALUE {
+    rb_id2sym(rb_intern(name.as_ptr()))
+}
+
+unsafe fn new_string(s: &str) -> VALUE {
+    rb_str_new(s.as_ptr() as *const c_char, s.len() as c_long)
+}
+
+unsafe fn call_method(receiver: VALUE, name: &CStr) -> VALUE {
+    rb_funcallv(receiver, rb_intern(name.as_ptr()), 0, ptr::null())
+}
+
+unsafe fn rstring_to_string(value: VALUE) -> String {
+    let bytes = slice::from_raw_parts(
+        ruvy_rstring_ptr(value) as *const u8,
+        ruvy_rstring_len(value) as usize,
+    );
+    String::from_utf8_lossy(bytes).into_owned()
+}
 
-    entries
-        .map(|r| r.map(|d| d.path()))
-        .filter(|r| r.is_ok() && r.as_deref().unwrap().is_file())
-        .for_each(|e| {
-            let prelude_contents = fs::read_to_string(e.unwrap()).unwrap();
-            eval(&prelude_contents).unwrap();
-        });
+pub fn is_cleaned_up() -> bool {
+    CLEANED_UP.load(Ordering::Relaxed)
 }
 
+/// Runs `at_exit` handlers and finalizers, and tears down the VM.
 pub fn cleanup_ruby() -> Result<()> {
-    const EXPECTED_SUCCESS_RET_VAL: i32 = 0;
-    // ruby_cleanup expects an integer as an argument that will be returned if it ran successfully.
-    let cleanup_status = unsafe { ruvy_wasm_sys::ruby_cleanup(EXPECTED_SUCCESS_RET_VAL) };
-    if cleanup_status != EXPECTED_SUCCESS_RET_VAL {
-        bail!("ruby_cleanup did not run successfully. Return value: {cleanup_status}");
+    // ruby_cleanup returns the status the process should exit with, which is
+    // non-zero if an `at_exit` handler raised or called `exit`.
+    let status = unsafe { ruvy_wasm_sys::ruby_cleanup(0) };
+    CLEANED_UP.store(true, Ordering::Relaxed);
+    if status != 0 {
+        return Err(Exit(status).into());
     }
     Ok(())
 }
@@ -49,12 +332,11 @@ pub fn cleanup_ruby() -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ruvy_wasm_sys::rb_num2int;
 
     #[test]
-    fn test_int() {
+    fn test_int() -> Result<()> {
         init_ruby();
-        let result = unsafe { rb_num2int(eval("1 + 1").unwrap()) };
-        assert_eq!(result, 2);
+        assert_eq!(2, eval("1 + 1")?.to::<i32>()?);
+        Ok(())
     }
 }
@@ -0,0 +1,24 @@
+# `Ruvy.input` and `Ruvy.output`, which read the input of the module from
+# stdin and write its output to stdout in the I/O format the module was built
+# with, using the `decode` and `encode` methods of `Ruvy::Native`.
+module Ruvy
+  class << self
+    # Returns the input of the module, read from stdin the first time.
+    def input
+      return @input if defined?(@input)
+
+      @input = Native.decode(STDIN.binmode.read)
+    end
+
+    # Writes `obj` to stdout as the output of the module, all at once.
+    def output(obj)
+      raise "Ruvy.output can only be called once" if @output_written
+
+      data = Native.encode(obj)
+      @output_written = true
+      STDOUT.binmode.write(data)
+      STDOUT.flush
+      nil
+    end
+  end
+end
@@ -0,0 +1,103 @@
+use std::{
+    env,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{bail, Result};
+use ruvy_wasm_sys::ruby_value_type_RUBY_T_STRING;
+
+#[cfg(feature = "json")]
+use crate::json;
+#[cfg(feature = "msgpack")]
+use crate::msgpack;
+use crate::{
+    native::{self, function, Exception},
+    runtime,
+    value::Value,
+};
+
+/// Ruby code defining `Ruvy.input` and `Ruvy.output` on top of `Ruvy::Native`.
+const RUVY_IO: &str = include_str!("ruvy_io.rb");
+
+/// Whether the module is being built, when stdin contains the Ruby code
+/// instead of the input of the module.
+static BUILDING: AtomicBool = AtomicBool::new(false);
+
+/// Defines the `Ruvy` module, whose `input` and `output` methods read and
+/// write data in the format named by `RUVY_IO_FORMAT`, or as is if it's not
+/// set.
+pub fn define() -> Result<()> {
+    BUILDING.store(true, Ordering::Relaxed);
+    let native = native::define_module("Ruvy")?.define_module("Native")?;
+    let format = env::var("RUVY_IO_FORMAT").unwrap_or_else(|_| "raw".to_string());
+    let (decode, encode) = match format.as_str() {
+        "raw" => (function!(decode_raw), function!(encode_raw)),
+        #[cfg(feature = "json")]
+        "json" => (function!(decode_json), function!(encode_json)),
+        #[cfg(feature = "msgpack")]
+        "msgpack" => (function!(decode_msgpack), function!(encode_msgpack)),
+        _ => bail!("The engine was built without support for the `{format}` I/O format"),
+    };
+    native.define_module_function("decode", decode)?;
+    native.define_module_function("encode", encode)?;
+    runtime::eval(RUVY_IO)?;
+    Ok(())
+}
+
+/// Lets `Ruvy.input` and `Ruvy.output` be called once the module runs.
+pub fn finish() {
+    BUILDING.store(false, Ordering::Relaxed);
+}
+
+/// Fails if the module is being built, since `method` would read the Ruby
+/// code or write to the output of the build.
+fn check_running(method: &str) -> Result<()> {
+    if BUILDING.load(Ordering::Relaxed) {
+        return Err(Exception::runtime_error(format!(
+            "{method} can't be called while the module is built"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn decode_raw(input: Value) -> Result<Value> {
+    check_running("Ruvy.input")?;
+    Ok(input)
+}
+
+fn encode_raw(output: Value) -> Result<Value> {
+    check_running("Ruvy.output")?;
+    if output.ruby_type() != ruby_value_type_RUBY_T_STRING {
+        return Err(Exception::type_error(format!(
+            "Ruvy.output expects a String with the raw I/O format, got {}",
+            output.class_name()
+        ))
+        .into());
+    }
+    Ok(output)
+}
+
+#[cfg(feature = "json")]
+fn decode_json(input: Value) -> Result<Value> {
+    check_running("Ruvy.input")?;
+    json::parse(input, false)
+}
+
+#[cfg(feature = "json")]
+fn encode_json(output: Value) -> Result<Value> {
+    check_running("Ruvy.output")?;
+    json::generate(output, false)
+}
+
+#[cfg(feature = "msgpack")]
+fn decode_msgpack(input: Value) -> Result<Value> {
+    check_running("Ruvy.input")?;
+    msgpack::unpack(input)
+}
+
+#[cfg(feature = "msgpack")]
+fn encode_msgpack(output: Value) -> Result<Value> {
+    check_running("Ruvy.output")?;
+    msgpack::pack(output)
+}
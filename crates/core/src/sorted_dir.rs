@@ -0,0 +1,59 @@
+use anyhow::Result;
+use std::env;
+
+use crate::runtime;
+
+/// Replaces the `Dir` methods listing directories in the order the host
+/// returns their entries with methods sorting them by name.
+const SORT: &str = r#"
+class << Dir
+  %i[entries children].each do |name|
+    alias_method :"__ruvy_unsorted_#{name}", name
+    define_method(name) { |*args, **kwargs| send(:"__ruvy_unsorted_#{name}", *args, **kwargs).sort }
+  end
+  { foreach: :entries, each_child: :children }.each do |name, list|
+    alias_method :"__ruvy_unsorted_#{name}", name
+    define_method(name) do |*args, **kwargs, &block|
+      entries = send(list, *args, **kwargs)
+      return entries.each unless block
+      entries.each(&block)
+      nil
+    end
+  end
+end
+"#;
+
+/// Restores the methods replaced by [`SORT`].
+const RESTORE: &str = r#"
+class << Dir
+  %i[entries children foreach each_child].each do |name|
+    alias_method name, :"__ruvy_unsorted_#{name}"
+    remove_method :"__ruvy_unsorted_#{name}"
+  end
+end
+"#;
+
+/// Sorts the entries `Dir` lists while the module is built, when
+/// `RUVY_REPRODUCIBLE` is set, so what the code does with them doesn't
+/// depend on the host's filesystem.
+pub struct SortedDir {
+    enabled: bool,
+}
+
+impl SortedDir {
+    pub fn start() -> Result<Self> {
+        let enabled = env::var_os("RUVY_REPRODUCIBLE").is_some();
+        if enabled {
+            runtime::eval(SORT)?;
+        }
+        Ok(Self { enabled })
+    }
+
+    /// Restores the original `Dir` methods before the snapshot is taken.
+    pub fn finish(self) -> Result<()> {
+        if self.enabled {
+            runtime::eval(RESTORE)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,476 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    os::raw::{c_char, c_int, c_long},
+    slice,
+};
+
+use anyhow::{anyhow, bail, Result};
+use ruvy_wasm_sys::{
+    rb_ary_entry, rb_ary_new_capa, rb_ary_push, rb_float_new, rb_funcallv, rb_gc_register_address,
+    rb_gc_unregister_address, rb_hash_aset, rb_hash_foreach, rb_hash_new, rb_id2sym, rb_inspect,
+    rb_intern2, rb_ll2inum, rb_num2dbl, rb_num2ll, rb_num2ull, rb_obj_classname, rb_str_new,
+    rb_sym2str, rb_ull2inum, rb_utf8_str_new, ruby_special_consts_RUBY_Qfalse,
+    ruby_special_consts_RUBY_Qnil, ruby_special_consts_RUBY_Qtrue, ruby_value_type,
+    ruby_value_type_RUBY_T_ARRAY, ruby_value_type_RUBY_T_BIGNUM, ruby_value_type_RUBY_T_FIXNUM,
+    ruby_value_type_RUBY_T_FLOAT, ruby_value_type_RUBY_T_HASH, ruby_value_type_RUBY_T_STRING,
+    ruby_value_type_RUBY_T_SYMBOL, ruvy_rarray_len, ruvy_rb_type, ruvy_rstring_len,
+    ruvy_rstring_ptr, st_retval_ST_CONTINUE, VALUE,
+};
+
+use crate::runtime::{self, protect};
+
+/// A Ruby object.
+///
+/// Values are only valid while the VM is initialized. The garbage collector
+/// doesn't see values only referenced from Rust, so code allocating Ruby
+/// objects while it holds such values needs to run in
+/// [`runtime::without_gc`], and values kept across calls, like in a `static`,
+/// need a [`Handle`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Value(VALUE);
+
+impl Value {
+    pub const NIL: Value = Value(ruby_special_consts_RUBY_Qnil as VALUE);
+    pub const TRUE: Value = Value(ruby_special_consts_RUBY_Qtrue as VALUE);
+    pub const FALSE: Value = Value(ruby_special_consts_RUBY_Qfalse as VALUE);
+
+    /// Wraps a `VALUE` returned by the Ruby C API.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must be a valid Ruby object or special constant.
+    pub unsafe fn from_raw(raw: VALUE) -> Self {
+        Self(raw)
+    }
+
+    pub fn as_raw(self) -> VALUE {
+        self.0
+    }
+
+    /// Converts `value` into a Ruby object, see [`IntoValue`].
+    pub fn new(value: impl IntoValue) -> Result<Self> {
+        value.into_value()
+    }
+
+    /// Converts the object into a Rust value, see [`FromValue`].
+    pub fn to<T: FromValue>(self) -> Result<T> {
+        T::from_value(self)
+    }
+
+    pub fn is_nil(self) -> bool {
+        self == Self::NIL
+    }
+
+    /// Whether Ruby considers the object true, which it does for anything but
+    /// `nil` and `false`.
+    pub fn is_truthy(self) -> bool {
+        self != Self::NIL && self != Self::FALSE
+    }
+
+    /// Calls the method named `method` on the object, converting any
+    /// exception it raises into an error.
+    pub fn call(self, method: &str, args: &[Value]) -> Result<Value> {
+        let id = intern(method)?;
+        protect(|| unsafe {
+            // `Value` is `repr(transparent)`, so the arguments are `VALUE`s.
+            Value(rb_funcallv(
+                self.0,
+                id,
+                args.len() as c_int,
+                args.as_ptr() as *const VALUE,
+            ))
+        })
+    }
+
+    pub fn class_name(self) -> String {
+        unsafe { std::ffi::CStr::from_ptr(rb_obj_classname(self.0)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Returns what `inspect` returns for the object.
+    pub fn inspect(self) -> Result<String> {
+        protect(|| unsafe { Value(rb_inspect(self.0)) })?.to()
+    }
+
+    /// Returns the type of the object, one of the `ruby_value_type_RUBY_T_*`
+    /// constants, like `rb_type` in C.
+    pub fn ruby_type(self) -> ruby_value_type {
+        unsafe { ruvy_rb_type(self.0) as ruby_value_type }
+    }
+
+    fn is_integer(self) -> bool {
+        let ruby_type = self.ruby_type();
+        ruby_type == ruby_value_type_RUBY_T_FIXNUM || ruby_type == ruby_value_type_RUBY_T_BIGNUM
+    }
+
+    /// Fails unless the object has the Ruby type `ruby_type`, whose class is
+    /// named `expected` in the error.
+    fn expect_type(self, ruby_type: ruby_value_type, expected: &str) -> Result<()> {
+        if self.ruby_type() != ruby_type {
+            bail!("Expected {expected}, got {}", self.class_name());
+        }
+        Ok(())
+    }
+
+    /// Returns the keys and values of a `Hash`, which stay referenced by the
+    /// hash.
+    pub fn pairs(self) -> Result<Vec<(Value, Value)>> {
+        unsafe extern "C" fn push(key: VALUE, value: VALUE, pairs: VALUE) -> c_int {
+            let pairs = &mut *(pairs as *mut Vec<(Value, Value)>);
+            pairs.push((Value(key), Value(value)));
+            st_retval_ST_CONTINUE as c_int
+        }
+
+        self.expect_type(ruby_value_type_RUBY_T_HASH, "Hash")?;
+        let mut pairs: Vec<(Value, Value)> = vec![];
+        unsafe {
+            rb_hash_foreach(
+                self.0,
+                Some(push),
+                &mut pairs as *mut Vec<(Value, Value)> as VALUE,
+            )
+        };
+        Ok(pairs)
+    }
+
+    /// Returns the bytes of a `String`, which are only valid until the string
+    /// is modified or collected.
+    pub fn string_bytes(&self) -> Result<&[u8]> {
+        self.expect_type(ruby_value_type_RUBY_T_STRING, "String")?;
+        Ok(unsafe {
+            slice::from_raw_parts(
+                ruvy_rstring_ptr(self.0) as *const u8,
+                ruvy_rstring_len(self.0) as usize,
+            )
+        })
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.inspect() {
+            Ok(inspected) => f.write_str(&inspected),
+            Err(_) => write!(f, "#<{}>", self.class_name()),
+        }
+    }
+}
+
+fn intern(name: &str) -> Result<ruvy_wasm_sys::ID> {
+    protect(|| unsafe { rb_intern2(name.as_ptr() as *const c_char, name.len() as c_long) })
+}
+
+/// A Ruby `Symbol`, e.g. `:name`.
+// Only the JSON and MessagePack modules create symbols.
+#[cfg_attr(not(any(feature = "json", feature = "msgpack")), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Symbol(pub String);
+
+/// A value kept alive by registering it with the garbage collector, so it can
+/// be stored anywhere.
+#[derive(Debug)]
+pub struct Handle(Box<VALUE>);
+
+impl Handle {
+    pub fn new(value: Value) -> Self {
+        let mut raw = Box::new(value.0);
+        unsafe { rb_gc_register_address(&mut *raw) };
+        Self(raw)
+    }
+
+    pub fn get(&self) -> Value {
+        Value(*self.0)
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        // The VM forgets about its registered addresses when it's torn down.
+        if !runtime::is_cleaned_up() {
+            unsafe { rb_gc_unregister_address(&mut *self.0) };
+        }
+    }
+}
+
+/// Converts Ruby objects into Rust values, failing if the object doesn't have
+/// the expected type or doesn't fit.
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Result<Self>;
+}
+
+/// Converts Rust values into Ruby objects.
+pub trait IntoValue {
+    fn into_value(self) -> Result<Value>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self> {
+        Ok(value)
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Result<Value> {
+        Ok(self)
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Result<Value> {
+        Ok(Value::NIL)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::TRUE => Ok(true),
+            Value::FALSE => Ok(false),
+            _ => bail!("Expected true or false, got {}", value.class_name()),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Result<Value> {
+        Ok(if self { Value::TRUE } else { Value::FALSE })
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: Value) -> Result<Self> {
+        if !value.is_integer() {
+            bail!("Expected Integer, got {}", value.class_name());
+        }
+        protect(|| unsafe { rb_num2ll(value.0) })
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Result<Value> {
+        protect(|| unsafe { Value(rb_ll2inum(self)) })
+    }
+}
+
+impl FromValue for u64 {
+    fn from_value(value: Value) -> Result<Self> {
+        if !value.is_integer() {
+            bail!("Expected Integer, got {}", value.class_name());
+        }
+        // `rb_num2ull` wraps negative integers around.
+        if value.call("negative?", &[])?.is_truthy() {
+            bail!("Integer {} is negative", value.inspect()?);
+        }
+        protect(|| unsafe { rb_num2ull(value.0) })
+    }
+}
+
+impl IntoValue for u64 {
+    fn into_value(self) -> Result<Value> {
+        protect(|| unsafe { Value(rb_ull2inum(self)) })
+    }
+}
+
+/// Converts integers through `i64` or `u64`, checking they fit.
+macro_rules! integer_conversions {
+    ($via:ty => $($int:ty),*) => {$(
+        impl FromValue for $int {
+            fn from_value(value: Value) -> Result<Self> {
+                let int = <$via>::from_value(value)?;
+                <$int>::try_from(int)
+                    .map_err(|_| anyhow!("Integer {int} doesn't fit in {}", stringify!($int)))
+            }
+        }
+
+        impl IntoValue for $int {
+            fn into_value(self) -> Result<Value> {
+                (self as $via).into_value()
+            }
+        }
+    )*};
+}
+
+integer_conversions!(i64 => i32, i16, isize);
+integer_conversions!(u64 => u32, u16, usize);
+
+/// Accepts integers as well as floats, like Ruby's numeric methods do.
+impl FromValue for f64 {
+    fn from_value(value: Value) -> Result<Self> {
+        if value.ruby_type() != ruby_value_type_RUBY_T_FLOAT && !value.is_integer() {
+            bail!("Expected Float, got {}", value.class_name());
+        }
+        protect(|| unsafe { rb_num2dbl(value.0) })
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Result<Value> {
+        protect(|| unsafe { Value(rb_float_new(self)) })
+    }
+}
+
+/// Fails if the string isn't valid UTF-8.
+impl FromValue for String {
+    fn from_value(value: Value) -> Result<Self> {
+        let bytes = value.string_bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| {
+            anyhow!(
+                "String {} is not valid UTF-8",
+                value.inspect().unwrap_or_default()
+            )
+        })
+    }
+}
+
+/// Creates a UTF-8 `String`.
+impl IntoValue for &str {
+    fn into_value(self) -> Result<Value> {
+        protect(|| unsafe {
+            Value(rb_utf8_str_new(
+                self.as_ptr() as *const c_char,
+                self.len() as c_long,
+            ))
+        })
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Result<Value> {
+        self.as_str().into_value()
+    }
+}
+
+/// Creates a binary (`ASCII-8BIT`) `String`.
+impl IntoValue for &[u8] {
+    fn into_value(self) -> Result<Value> {
+        protect(|| unsafe {
+            Value(rb_str_new(
+                self.as_ptr() as *const c_char,
+                self.len() as c_long,
+            ))
+        })
+    }
+}
+
+impl FromValue for Symbol {
+    fn from_value(value: Value) -> Result<Self> {
+        value.expect_type(ruby_value_type_RUBY_T_SYMBOL, "Symbol")?;
+        let name = protect(|| unsafe { Value(rb_sym2str(value.0)) })?;
+        Ok(Symbol(name.to()?))
+    }
+}
+
+impl IntoValue for Symbol {
+    fn into_value(self) -> Result<Value> {
+        let id = intern(&self.0)?;
+        protect(|| unsafe { Value(rb_id2sym(id)) })
+    }
+}
+
+/// Converts `nil` to `None`.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> Result<Self> {
+        if value.is_nil() {
+            return Ok(None);
+        }
+        T::from_value(value).map(Some)
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Result<Value> {
+        match self {
+            Some(value) => value.into_value(),
+            None => Ok(Value::NIL),
+        }
+    }
+}
+
+// Converting containers allocates objects while the container and the objects
+// converted so far are only referenced from Rust, so it runs without GC.
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value) -> Result<Self> {
+        value.expect_type(ruby_value_type_RUBY_T_ARRAY, "Array")?;
+        runtime::without_gc(|| {
+            // The array may change while its elements are converted.
+            let mut elements = vec![];
+            let mut index = 0;
+            while index < unsafe { ruvy_rarray_len(value.0) } {
+                elements.push(T::from_value(Value(unsafe {
+                    rb_ary_entry(value.0, index)
+                }))?);
+                index += 1;
+            }
+            Ok(elements)
+        })
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Result<Value> {
+        runtime::without_gc(|| {
+            let array = protect(|| unsafe { Value(rb_ary_new_capa(self.len() as c_long)) })?;
+            for element in self {
+                let element = element.into_value()?;
+                protect(|| unsafe { rb_ary_push(array.0, element.0) })?;
+            }
+            Ok(array)
+        })
+    }
+}
+
+impl<K: FromValue + Eq + Hash, V: FromValue> FromValue for HashMap<K, V> {
+    fn from_value(value: Value) -> Result<Self> {
+        runtime::without_gc(|| {
+            let pairs = value.pairs()?;
+            let mut map = HashMap::with_capacity(pairs.len());
+            for (key, value) in pairs {
+                map.insert(K::from_value(key)?, V::from_value(value)?);
+            }
+            Ok(map)
+        })
+    }
+}
+
+impl<K: IntoValue, V: IntoValue> IntoValue for HashMap<K, V> {
+    fn into_value(self) -> Result<Value> {
+        runtime::without_gc(|| {
+            let hash = protect(|| unsafe { Value(rb_hash_new()) })?;
+            for (key, value) in self {
+                let key = key.into_value()?;
+                let value = value.into_value()?;
+                protect(|| unsafe { rb_hash_aset(hash.0, key.0, value.0) })?;
+            }
+            Ok(hash)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{eval, init_ruby};
+
+    #[test]
+    fn test_conversions() -> Result<()> {
+        init_ruby();
+        let value = Value::new(vec![Some("ruvy".to_string()), None])?;
+        assert_eq!(r#"["ruvy", nil]"#, value.inspect()?);
+        assert_eq!(
+            vec![Some("ruvy".to_string()), None],
+            value.to::<Vec<Option<String>>>()?
+        );
+        assert_eq!(u64::MAX, Value::new(u64::MAX)?.to::<u64>()?);
+        assert!(eval("-1")?.to::<u64>().is_err());
+        assert!(eval("2**40")?.to::<i32>().is_err());
+        assert_eq!(
+            "Expected Integer, got String",
+            eval("'1'")?.to::<i64>().unwrap_err().to_string()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! Selects the Ruby files to preload, shared by the engine, which loads them,
+//! and the host, which records them in the module's metadata.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Name of the file that lists the files to preload from its directory.
+const MANIFEST_NAME: &str = "ruvy-preload.txt";
+
+/// Returns the Ruby files to preload from `path` in the order to load them.
+///
+/// A file is preloaded on its own. Directories are searched recursively for
+/// `.rb` files, which are sorted by path, unless they contain a
+/// `ruvy-preload.txt` manifest.
+pub fn files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        if !path.is_file() {
+            bail!("Preload path {} does not exist", path.display());
+        }
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let manifest_path = path.join(MANIFEST_NAME);
+    if !manifest_path.is_file() {
+        return ruby_files(path);
+    }
+    let manifest = fs::read_to_string(&manifest_path)
+        .with_context(|| format!("Could not read {}", manifest_path.display()))?;
+    let manifest = Manifest::parse(&manifest);
+
+    let mut files = vec![];
+    for entry in manifest.entries {
+        let entry_path = path.join(entry);
+        let entry_files = if entry_path.is_dir() {
+            ruby_files(&entry_path)?
+        } else if entry_path.is_file() {
+            vec![entry_path]
+        } else {
+            bail!(
+                "{} lists `{entry}`, which does not exist",
+                manifest_path.display()
+            );
+        };
+        for file in entry_files {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    let excludes: Vec<_> = manifest
+        .excludes
+        .iter()
+        .map(|exclude| path.join(exclude))
+        .collect();
+    files.retain(|file| !excludes.iter().any(|exclude| file.starts_with(exclude)));
+    Ok(files)
+}
+
+/// Returns the `.rb` files in `dir` and its subdirectories, sorted by path.
+///
+/// Symbolic links to directories aren't followed, so links pointing back up
+/// the tree can't make the search loop forever.
+fn ruby_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries =
+            fs::read_dir(&dir).with_context(|| format!("Could not read {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|extension| extension == "rb") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Contents of a `ruvy-preload.txt` manifest.
+///
+/// Each line is a path relative to the manifest's directory. Files and
+/// directories are loaded in the order they're listed, and files that
+/// aren't listed aren't loaded. Lines starting with `!` exclude a file or
+/// directory, which is useful to skip part of a listed directory. Empty
+/// lines and lines starting with `#` are ignored.
+#[derive(Debug, Default, PartialEq)]
+struct Manifest<'a> {
+    entries: Vec<&'a str>,
+    excludes: Vec<&'a str>,
+}
+
+impl<'a> Manifest<'a> {
+    fn parse(manifest: &'a str) -> Self {
+        let mut parsed = Self::default();
+        for line in manifest.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.strip_prefix('!') {
+                Some(exclude) => parsed.excludes.push(exclude.trim_start()),
+                None => parsed.entries.push(line),
+            }
+        }
+        parsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let prelude = dir.path().join("prelude");
+        fs::create_dir_all(prelude.join("lib/legacy"))?;
+        for file in [
+            "setup.rb",
+            "lib/b.rb",
+            "lib/a.rb",
+            "lib/notes.txt",
+            "lib/legacy/c.rb",
+        ] {
+            fs::write(prelude.join(file), "")?;
+        }
+        assert_eq!(
+            vec![
+                prelude.join("lib/a.rb"),
+                prelude.join("lib/b.rb"),
+                prelude.join("lib/legacy/c.rb"),
+                prelude.join("setup.rb"),
+            ],
+            files(&prelude)?
+        );
+
+        fs::write(
+            prelude.join(MANIFEST_NAME),
+            "setup.rb\nlib/\n! lib/legacy\n",
+        )?;
+        assert_eq!(
+            vec![
+                prelude.join("setup.rb"),
+                prelude.join("lib/a.rb"),
+                prelude.join("lib/b.rb"),
+            ],
+            files(&prelude)?
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_files_symlink_cycle() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("main.rb"), "")?;
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("loop"))?;
+        assert_eq!(vec![dir.path().join("main.rb")], files(dir.path())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = Manifest::parse("# Loaded first\nsetup.rb\n\nlib/\n! lib/legacy\n");
+        assert_eq!(
+            Manifest {
+                entries: vec!["setup.rb", "lib/"],
+                excludes: vec!["lib/legacy"],
+            },
+            manifest
+        );
+    }
+}
@@ -1,8 +1,8 @@
 use std::{
     fs::{self, File},
     io::Write,
-    path::Path,
-    process::Command,
+    path::{Path, PathBuf},
+    process::{self, Command},
 };
 
 use anyhow::{anyhow, bail, Error, Result};
@@ -16,10 +16,13 @@ use hyper_util::{client::legacy::Client, rt::TokioExecutor};
 use lazy_static::lazy_static;
 use tokio::runtime::Runtime;
 
-const RUBY_WASM_VERSION: &str = "2.1.0";
-const RUBY_WASM_RUBY_VERSION: &str = "3_2";
+pub const RUBY_WASM_VERSION: &str = "2.1.0";
+pub const RUBY_WASM_RUBY_VERSION: &str = "3_2";
 const RUBY_WASM_TARGET: &str = "wasm32-unknown-wasi";
-const RUBY_WASM_PROFILE: &str = "minimal";
+pub const RUBY_WASM_PROFILE: &str = "minimal";
+
+pub const WASI_SDK_VERSION_MAJOR: usize = 20;
+pub const WASI_SDK_VERSION_MINOR: usize = 0;
 
 lazy_static! {
     static ref RT: Runtime = tokio::runtime::Builder::new_current_thread()
@@ -70,6 +73,38 @@ pub fn download_ruby_wasm(path: &Path) -> Result<()> {
     download(format!("https://github.com/ruby/ruby.wasm/releases/download/{RUBY_WASM_VERSION}/ruby-{RUBY_WASM_RUBY_VERSION}-{RUBY_WASM_TARGET}-{RUBY_WASM_PROFILE}.tar.gz"), path)
 }
 
+/// Downloads and unpacks the default ruby.wasm, unless an earlier build did,
+/// and returns its directory.
+///
+/// The directory is shared by the build scripts of the workspace, so the
+/// engine and the standard library embedded in ruvy come from the same
+/// download.
+pub fn ruby_wasm_dir() -> Result<PathBuf> {
+    let base_name = ruby_wasm_base_name();
+    let parent = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../target/ruby-wasm");
+    let dir = parent.join(&base_name);
+    if dir.exists() {
+        return Ok(dir);
+    }
+
+    // Builds running at the same time may download it too, so each one
+    // unpacks it in its own directory that is then renamed.
+    let temp_dir = parent.join(format!("{base_name}.{}", process::id()));
+    fs::create_dir_all(&temp_dir)?;
+    let archive_path = temp_dir.join(format!("{base_name}.tar.gz"));
+    download_ruby_wasm(&archive_path)?;
+    // Need to strip archive name, `usr`, and `local`.
+    extract_tar(&archive_path, &temp_dir, 3)?;
+    fs::remove_file(&archive_path)?;
+    if let Err(err) = fs::rename(&temp_dir, &dir) {
+        fs::remove_dir_all(&temp_dir)?;
+        if !dir.exists() {
+            return Err(err.into());
+        }
+    }
+    Ok(dir)
+}
+
 pub fn extract_tar(archive: &Path, extract_to: &Path, components_to_strip: i32) -> Result<()> {
     if !extract_to.exists() {
         fs::create_dir(extract_to)?;
@@ -0,0 +1,103 @@
+use std::{
+    env,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use flate2::{write::GzEncoder, Compression};
+
+fn main() -> Result<()> {
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    let engine_destination = out_dir.join("engine.wasm");
+    let stdlib_destination = out_dir.join("stdlib.tar.gz");
+    // Clippy runs through a wrapper, and doesn't need the engine or the
+    // standard library to check the code.
+    let is_clippy = env::var("RUSTC_WORKSPACE_WRAPPER").is_ok_and(|wrapper| {
+        Path::new(&wrapper).file_stem().unwrap_or_default() == "clippy-driver"
+    });
+    if is_clippy {
+        fs::write(engine_destination, [])?;
+        fs::write(stdlib_destination, [])?;
+        println!(
+            "cargo:warning=using stubbed engine.wasm and standard library for static analysis"
+        );
+    } else {
+        println!("cargo:rerun-if-changed=build.rs");
+        let engine_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../target/wasm32-wasip1/release/core.wasm");
+        println!("cargo:rerun-if-changed={}", engine_path.to_str().unwrap());
+        fs::copy(engine_path, engine_destination)?;
+
+        let stdlib = ruby_wasm_path()?.join("lib/ruby/3.2.0");
+        pack_stdlib(&stdlib, &stdlib_destination)?;
+    }
+    set_version_env_vars();
+    Ok(())
+}
+
+/// Packs the standard library of ruby.wasm in `stdlib` into an archive that
+/// ruvy embeds, so it doesn't depend on the files it was built with.
+fn pack_stdlib(stdlib: &Path, destination: &Path) -> Result<()> {
+    let mut archive = tar::Builder::new(GzEncoder::new(
+        File::create(destination)?,
+        Compression::default(),
+    ));
+    archive.append_dir_all("stdlib", stdlib)?;
+    archive.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Records the versions of ruby.wasm and the WASI SDK the engine is built
+/// with, which `ruvy-wasm-sys` reads from the same environment variables.
+fn set_version_env_vars() {
+    let custom_ruby_wasm = env::var_os("RUVY_WASM_SYS_RUBY_PATH").is_some();
+    let ruby_wasm_versions = [
+        (
+            "RUVY_RUBY_WASM_VERSION",
+            ruby_wasm_assets::RUBY_WASM_VERSION,
+        ),
+        (
+            "RUVY_RUBY_WASM_RUBY_VERSION",
+            ruby_wasm_assets::RUBY_WASM_RUBY_VERSION,
+        ),
+        (
+            "RUVY_RUBY_WASM_PROFILE",
+            ruby_wasm_assets::RUBY_WASM_PROFILE,
+        ),
+    ];
+    for (name, version) in ruby_wasm_versions {
+        let version = if custom_ruby_wasm { "custom" } else { version };
+        println!("cargo:rustc-env={name}={version}");
+    }
+
+    for name in [
+        "RUVY_WASM_SYS_RUBY_PATH",
+        "RUVY_WASM_SYS_WASI_SDK_PATH",
+        "RUVY_WASM_SYS_WASI_SDK_MAJOR_VERSION",
+        "RUVY_WASM_SYS_WASI_SDK_MINOR_VERSION",
+    ] {
+        println!("cargo:rerun-if-env-changed={name}");
+    }
+    let wasi_sdk_version = if env::var_os("RUVY_WASM_SYS_WASI_SDK_PATH").is_some() {
+        "custom".to_string()
+    } else {
+        let major = env::var("RUVY_WASM_SYS_WASI_SDK_MAJOR_VERSION")
+            .unwrap_or(ruby_wasm_assets::WASI_SDK_VERSION_MAJOR.to_string());
+        let minor = env::var("RUVY_WASM_SYS_WASI_SDK_MINOR_VERSION")
+            .unwrap_or(ruby_wasm_assets::WASI_SDK_VERSION_MINOR.to_string());
+        format!("{major}.{minor}")
+    };
+    println!("cargo:rustc-env=RUVY_WASI_SDK_VERSION={wasi_sdk_version}");
+}
+
+// Uses the same ruby.wasm as `ruvy-wasm-sys` so the standard library matches
+// the engine.
+fn ruby_wasm_path() -> Result<PathBuf> {
+    const RUBY_WASM_PATH_ENV_VAR: &str = "RUVY_WASM_SYS_RUBY_PATH";
+    println!("cargo:rerun-if-env-changed={RUBY_WASM_PATH_ENV_VAR}");
+    if let Ok(path) = env::var(RUBY_WASM_PATH_ENV_VAR) {
+        return Ok(path.into());
+    }
+    ruby_wasm_assets::ruby_wasm_dir()
+}
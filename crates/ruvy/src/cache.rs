@@ -0,0 +1,240 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{metadata::sha256, stdlib, Diagnostic, Limits, Metadata, Output, SizeReport};
+
+/// Name of the file containing the module of a cache entry.
+const MODULE_NAME: &str = "module.wasm";
+
+/// Name of the file containing the rest of a cache entry as JSON.
+const ENTRY_NAME: &str = "entry.json";
+
+/// Modules built before, each in a directory of `dir` named after the hash of
+/// what it was built from, see [`key`].
+#[derive(Debug)]
+pub(crate) struct Cache {
+    dir: PathBuf,
+}
+
+/// What a build printed and loaded, stored next to its module.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    stdout: Vec<u8>,
+    diagnostics: Vec<Diagnostic>,
+    size_report: Option<SizeReport>,
+    /// Files loaded with `require` and their SHA-256 hashes. The module is
+    /// only reused if none of them changed.
+    loaded_files: Vec<(PathBuf, String)>,
+}
+
+/// What a module is built from besides the files loaded with `require`.
+#[derive(Serialize)]
+struct Inputs<'a> {
+    /// Contains the hashes of the source, preloaded files and gems, and the
+    /// build options.
+    metadata: &'a Metadata,
+    engine_sha256: String,
+    limits: &'a Limits,
+    /// Canonical host paths of the input file, load paths and preload paths,
+    /// which decide what `require` and `require_relative` find, while the
+    /// metadata only has their names.
+    paths: Vec<PathBuf>,
+    /// The time reproducible builds run at.
+    source_date_epoch: Option<String>,
+    /// The standard library libraries are loaded from, see [`stdlib::id`].
+    stdlib: String,
+}
+
+/// Returns the cache key of a module built by `engine` from what `metadata`
+/// describes, with the given `limits`.
+///
+/// `paths` are the input file, load paths and preload paths the module is
+/// built from.
+pub(crate) fn key(
+    metadata: &Metadata,
+    engine: &[u8],
+    limits: &Limits,
+    paths: &[&Path],
+) -> Result<String> {
+    let inputs = Inputs {
+        metadata,
+        engine_sha256: sha256(engine),
+        limits,
+        paths: paths
+            .iter()
+            .map(|path| {
+                path.canonicalize()
+                    .with_context(|| format!("Could not find {}", path.display()))
+            })
+            .collect::<Result<_>>()?,
+        source_date_epoch: metadata
+            .options
+            .reproducible
+            .then(|| env::var("SOURCE_DATE_EPOCH").ok())
+            .flatten(),
+        stdlib: stdlib::id()?,
+    };
+    Ok(sha256(&serde_json::to_vec(&inputs)?))
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Returns the output stored for `key`, unless one of the files it loaded
+    /// changed since.
+    ///
+    /// Entries that can't be read are ignored, so they're replaced by the
+    /// next [`Cache::put`].
+    pub fn get(&self, key: &str) -> Option<Output> {
+        let dir = self.dir.join(key);
+        let entry: Entry = serde_json::from_slice(&fs::read(dir.join(ENTRY_NAME)).ok()?).ok()?;
+        for (path, hash) in &entry.loaded_files {
+            if sha256(&fs::read(path).ok()?) != *hash {
+                return None;
+            }
+        }
+        Some(Output {
+            wasm: fs::read(dir.join(MODULE_NAME)).ok()?,
+            stdout: entry.stdout,
+            diagnostics: entry.diagnostics,
+            size_report: entry.size_report,
+            heap_report: None,
+            loaded_files: entry
+                .loaded_files
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect(),
+        })
+    }
+
+    /// Stores `output` for `key`.
+    pub fn put(&self, key: &str, output: &Output) -> Result<()> {
+        self.write(key, output).with_context(|| {
+            format!(
+                "Could not write to the build cache at {}",
+                self.dir.display()
+            )
+        })
+    }
+
+    fn write(&self, key: &str, output: &Output) -> Result<()> {
+        let mut loaded_files = vec![];
+        for path in &output.loaded_files {
+            loaded_files.push((path.clone(), sha256(&fs::read(path)?)));
+        }
+        let entry = Entry {
+            stdout: output.stdout.clone(),
+            diagnostics: output.diagnostics.clone(),
+            size_report: output.size_report,
+            loaded_files,
+        };
+
+        // Builds running at the same time may store the same entry, so each
+        // entry is written to a temporary directory that is then renamed.
+        fs::create_dir_all(&self.dir)?;
+        let temp_dir = tempfile::Builder::new()
+            .prefix(".tmp")
+            .tempdir_in(&self.dir)?;
+        fs::write(temp_dir.path().join(MODULE_NAME), &output.wasm)?;
+        fs::write(
+            temp_dir.path().join(ENTRY_NAME),
+            serde_json::to_vec(&entry)?,
+        )?;
+        let dir = self.dir.join(key);
+        // The entry is outdated, since it wasn't reused. Another build may
+        // have removed it already.
+        let _ = fs::remove_dir_all(&dir);
+        rename_or_discard(temp_dir.keep(), &dir)
+    }
+}
+
+/// Renames the directory at `from` to `to`, or removes it if another build
+/// stored the entry first.
+fn rename_or_discard(from: PathBuf, to: &Path) -> Result<()> {
+    match fs::rename(&from, to) {
+        Ok(()) => Ok(()),
+        Err(_) if to.is_dir() => Ok(fs::remove_dir_all(&from)?),
+        Err(err) => {
+            let _ = fs::remove_dir_all(&from);
+            Err(err.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{metadata::BuildOptions, IoFormat, Target};
+
+    #[test]
+    fn test_get_put() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let loaded_file = dir.path().join("helper.rb");
+        fs::write(&loaded_file, "HELPER = 1")?;
+        let cache = Cache::new(dir.path().join("cache"));
+        let output = Output {
+            wasm: b"\0asm".to_vec(),
+            stdout: b"built\n".to_vec(),
+            diagnostics: vec![],
+            size_report: None,
+            heap_report: None,
+            loaded_files: vec![loaded_file.clone()],
+        };
+        assert!(cache.get("key").is_none());
+        cache.put("key", &output)?;
+        let cached = cache.get("key").unwrap();
+        assert_eq!(output.wasm, cached.wasm);
+        assert_eq!(output.stdout, cached.stdout);
+
+        fs::write(&loaded_file, "HELPER = 2")?;
+        assert!(cache.get("key").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_key() -> Result<()> {
+        // Two projects with the same input file, which requires a different
+        // `helper.rb` in each of them.
+        let dir = tempfile::tempdir()?;
+        let mut keys = vec![];
+        for (project, helper) in [("a", "HELPER = 1"), ("b", "HELPER = 2")] {
+            let project_dir = dir.path().join(project);
+            fs::create_dir(&project_dir)?;
+            let input_path = project_dir.join("main.rb");
+            let source = "require_relative 'helper'";
+            fs::write(&input_path, source)?;
+            fs::write(project_dir.join("helper.rb"), helper)?;
+            let metadata = Metadata::new(
+                source,
+                Some(&input_path),
+                &[],
+                &[],
+                BuildOptions {
+                    load_paths: vec![],
+                    stdlib: vec![],
+                    exports: vec![],
+                    dynamic: false,
+                    target: Target::Wasi,
+                    io_format: IoFormat::Raw,
+                    optimize: false,
+                    reproducible: false,
+                },
+            )?;
+            keys.push(key(
+                &metadata,
+                b"engine",
+                &Limits::default(),
+                &[&input_path],
+            )?);
+        }
+        assert_ne!(keys[0], keys[1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// A message Ruby printed on stderr while the module was built.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Where in the Ruby code the message comes from, if it starts with a
+    /// `path:line:` location like warnings and exceptions do.
+    pub location: Option<Location>,
+    /// The message as printed, including its location and, for exceptions,
+    /// the rest of the backtrace.
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The exception or error that failed the build.
+    Error,
+    /// A warning printed by Ruby, e.g. for a method redefinition.
+    Warning,
+    /// Anything else, like the output of `warn` or `$stderr.puts`.
+    Note,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Location {
+    pub path: String,
+    pub line: u32,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// The error returned when the Ruby engine exits while the module is built,
+/// e.g. because the code has a syntax error or raised an exception.
+#[derive(Debug)]
+pub struct CompileError {
+    pub exit_code: i32,
+    /// What Ruby printed on stdout before exiting.
+    pub stdout: Vec<u8>,
+    /// What Ruby printed on stderr, ending with the error when there is one.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.diagnostics.last() {
+            Some(diagnostic) if diagnostic.severity == Severity::Error => {
+                write!(f, "{diagnostic}")
+            }
+            _ => write!(f, "Ruby exited with status {}", self.exit_code),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Splits what Ruby printed on `stderr` into diagnostics.
+///
+/// Indented lines continue the previous diagnostic, like the `from` lines of
+/// backtraces and the code snippets of syntax errors. When the build
+/// `failed`, the last diagnostic is the error that failed it.
+pub fn parse(stderr: &str, failed: bool) -> Vec<Diagnostic> {
+    let mut messages: Vec<String> = vec![];
+    for line in stderr.lines() {
+        match messages.last_mut() {
+            Some(message) if line.starts_with([' ', '\t']) => {
+                message.push('\n');
+                message.push_str(line);
+            }
+            _ => messages.push(line.to_string()),
+        }
+    }
+
+    let count = messages.len();
+    messages
+        .into_iter()
+        .enumerate()
+        .map(|(index, message)| {
+            let (location, rest) = match parse_location(&message) {
+                Some((location, rest)) => (Some(location), rest),
+                None => (None, message.as_str()),
+            };
+            let severity = if rest.starts_with("warning:") {
+                Severity::Warning
+            } else if failed && index + 1 == count {
+                Severity::Error
+            } else {
+                Severity::Note
+            };
+            Diagnostic {
+                severity,
+                location,
+                message,
+            }
+        })
+        .collect()
+}
+
+/// Splits a `path:line:` prefix from `message`.
+fn parse_location(message: &str) -> Option<(Location, &str)> {
+    let first_line = message.lines().next()?;
+    for (colon, _) in first_line.match_indices(':') {
+        let after = &first_line[colon + 1..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if colon == 0 || digits == 0 || !after[digits..].starts_with(':') {
+            continue;
+        }
+        let location = Location {
+            path: first_line[..colon].to_string(),
+            line: after[..digits].parse().ok()?,
+        };
+        return Some((location, message[colon + digits + 2..].trim_start()));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let stderr = "/app/main.rb:1: warning: method redefined; discarding old run\n\
+            checking input\n\
+            /app/main.rb:2:in `fail!': something went wrong (ArgumentError)\n\
+            \tfrom /app/main.rb:5:in `<main>'\n";
+        let diagnostics = parse(stderr, true);
+        assert_eq!(
+            vec![
+                (Severity::Warning, Some(1)),
+                (Severity::Note, None),
+                (Severity::Error, Some(2)),
+            ],
+            diagnostics
+                .iter()
+                .map(|d| (d.severity, d.location.as_ref().map(|l| l.line)))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "/app/main.rb",
+            diagnostics[2].location.as_ref().unwrap().path
+        );
+        assert!(diagnostics[2]
+            .message
+            .ends_with("\n\tfrom /app/main.rb:5:in `<main>'"));
+    }
+}
@@ -0,0 +1,150 @@
+use wasm_encoder::{
+    CodeSection, DataCountSection, DataSection, EntityType, ExportKind, ExportSection, Function,
+    FunctionSection, ImportSection, InstructionSink, MemoryType, Module, TypeSection, ValType,
+};
+
+/// The name of the module modules compiled in dynamic mode import the Ruby
+/// engine from.
+///
+/// This needs to be bumped whenever the engine's exports change in a way that
+/// is incompatible with previously compiled modules.
+pub const ENGINE_IMPORT_NAMESPACE: &str = "ruvy_engine_v1";
+
+const REALLOC_FN: u32 = 0;
+const EVAL_FN: u32 = 1;
+const INVOKE_FN: u32 = 2;
+const IMPORTED_FN_COUNT: u32 = 3;
+
+const CODE_SEGMENT: u32 = 0;
+
+/// Generates a module containing `ruby_code` that evaluates it using the
+/// memory and functions exported by the Ruby engine module.
+///
+/// If `methods` is empty, the module exports a `_start` function evaluating
+/// the code. Otherwise, it exports a function named after each entry in
+/// `export_names` that evaluates the code and then calls the top-level Ruby
+/// method at the same index in `methods`.
+pub fn generate(ruby_code: &str, methods: &[String], export_names: &[String]) -> Vec<u8> {
+    let mut module = Module::new();
+
+    let mut types = TypeSection::new();
+    // (orig_ptr, orig_len, alignment, new_len) -> ptr
+    let realloc_type = 0;
+    types.ty().function([ValType::I32; 4], [ValType::I32]);
+    // (code_ptr, code_len) -> ()
+    let eval_type = 1;
+    types.ty().function([ValType::I32; 2], []);
+    // (code_ptr, code_len, method_ptr, method_len) -> ()
+    let invoke_type = 2;
+    types.ty().function([ValType::I32; 4], []);
+    let export_type = 3;
+    types.ty().function([], []);
+    module.section(&types);
+
+    let mut imports = ImportSection::new();
+    imports.import(
+        ENGINE_IMPORT_NAMESPACE,
+        "canonical_abi_realloc",
+        EntityType::Function(realloc_type),
+    );
+    imports.import(
+        ENGINE_IMPORT_NAMESPACE,
+        "ruvy_eval",
+        EntityType::Function(eval_type),
+    );
+    imports.import(
+        ENGINE_IMPORT_NAMESPACE,
+        "ruvy_invoke",
+        EntityType::Function(invoke_type),
+    );
+    imports.import(
+        ENGINE_IMPORT_NAMESPACE,
+        "memory",
+        MemoryType {
+            minimum: 0,
+            maximum: None,
+            memory64: false,
+            shared: false,
+            page_size_log2: None,
+        },
+    );
+    module.section(&imports);
+
+    let export_names = if methods.is_empty() {
+        vec!["_start"]
+    } else {
+        export_names.iter().map(String::as_str).collect()
+    };
+
+    let mut functions = FunctionSection::new();
+    for _ in &export_names {
+        functions.function(export_type);
+    }
+    module.section(&functions);
+
+    let mut export_section = ExportSection::new();
+    for (index, name) in export_names.iter().enumerate() {
+        export_section.export(name, ExportKind::Func, IMPORTED_FN_COUNT + index as u32);
+    }
+    module.section(&export_section);
+
+    let mut data = DataSection::new();
+    data.passive(ruby_code.bytes());
+    for method in methods {
+        data.passive(method.bytes());
+    }
+    module.section(&DataCountSection { count: data.len() });
+
+    let code_len = ruby_code.len() as u32;
+    let mut code = CodeSection::new();
+    if methods.is_empty() {
+        let code_ptr = 0;
+        let mut start = Function::new([(1, ValType::I32)]);
+        let mut instructions = start.instructions();
+        copy_segment(&mut instructions, CODE_SEGMENT, code_len, code_ptr);
+        instructions
+            .data_drop(CODE_SEGMENT)
+            .local_get(code_ptr)
+            .i32_const(code_len as i32)
+            .call(EVAL_FN)
+            .end();
+        code.function(&start);
+    }
+    for (index, method) in methods.iter().enumerate() {
+        let (code_ptr, method_ptr) = (0, 1);
+        let method_segment = CODE_SEGMENT + 1 + index as u32;
+        let method_len = method.len() as u32;
+        let mut export = Function::new([(2, ValType::I32)]);
+        let mut instructions = export.instructions();
+        copy_segment(&mut instructions, CODE_SEGMENT, code_len, code_ptr);
+        copy_segment(&mut instructions, method_segment, method_len, method_ptr);
+        instructions
+            .local_get(code_ptr)
+            .i32_const(code_len as i32)
+            .local_get(method_ptr)
+            .i32_const(method_len as i32)
+            .call(INVOKE_FN)
+            .end();
+        code.function(&export);
+    }
+    module.section(&code);
+
+    module.section(&data);
+
+    module.finish()
+}
+
+/// Allocates `len` bytes in the engine's memory, stores the address in
+/// `ptr_local` and copies the passive data `segment` there.
+fn copy_segment(instructions: &mut InstructionSink, segment: u32, len: u32, ptr_local: u32) {
+    instructions
+        .i32_const(0)
+        .i32_const(0)
+        .i32_const(1)
+        .i32_const(len as i32)
+        .call(REALLOC_FN)
+        .local_tee(ptr_local)
+        .i32_const(0)
+        .i32_const(len as i32)
+        .memory_init(0, segment);
+}
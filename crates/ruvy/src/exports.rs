@@ -0,0 +1,137 @@
+use anyhow::{anyhow, bail, Result};
+use wasm_encoder::{
+    reencode::{Reencode, RoundtripReencoder},
+    CodeSection, ExportKind, ExportSection, Function, FunctionSection, Module, RawSection,
+};
+use wasmparser::{ExternalKind, Parser, Payload, TypeRef};
+
+/// The engine function called by the exports added by [`add_ruby_exports`].
+const CALL_EXPORT_FN: &str = "ruvy_call_export";
+
+/// Returns a copy of `wasm` without the export named `name`.
+pub fn remove(wasm: &[u8], name: &str) -> Result<Vec<u8>> {
+    rewrite(wasm, &[], |export_name| export_name != name)
+}
+
+/// Returns a copy of `wasm`, a wizened Ruby engine, that exports a function
+/// named after each entry in `names` instead of `_start`.
+///
+/// Each export calls the top-level Ruby method recorded in the snapshot at
+/// the same index, which usually has the same name as the export.
+pub fn add_ruby_exports(wasm: &[u8], names: &[String]) -> Result<Vec<u8>> {
+    rewrite(wasm, names, |export_name| export_name != "_start")
+}
+
+/// Copies `wasm` while only keeping exports for which `keep` returns true and
+/// adding an export calling `ruvy_call_export` for each entry in `methods`.
+fn rewrite(wasm: &[u8], methods: &[String], keep: impl Fn(&str) -> bool) -> Result<Vec<u8>> {
+    let mut imported_fn_count = 0;
+    let mut defined_fn_count = 0;
+    let mut unit_type = None;
+    let mut call_export_fn = None;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::TypeSection(reader) => {
+                let sub_types = reader
+                    .into_iter_err_on_gc_types()
+                    .collect::<Result<Vec<_>, _>>()?;
+                unit_type = sub_types
+                    .iter()
+                    .position(|ty| ty.params().is_empty() && ty.results().is_empty())
+                    .map(|index| index as u32);
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    if matches!(import?.ty, TypeRef::Func(_)) {
+                        imported_fn_count += 1;
+                    }
+                }
+            }
+            Payload::FunctionSection(reader) => defined_fn_count = reader.count(),
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    if export.name == CALL_EXPORT_FN && export.kind == ExternalKind::Func {
+                        call_export_fn = Some(export.index);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut added = Vec::new();
+    if !methods.is_empty() {
+        let unit_type = unit_type.ok_or_else(|| anyhow!("Module has no `[] -> []` type"))?;
+        let call_export_fn =
+            call_export_fn.ok_or_else(|| anyhow!("Module does not export `{CALL_EXPORT_FN}`"))?;
+        for (index, method) in methods.iter().enumerate() {
+            let mut function = Function::new([]);
+            function
+                .instructions()
+                .i32_const(index as i32)
+                .call(call_export_fn)
+                .end();
+            added.push((method, unit_type, function));
+        }
+    }
+    let first_added_fn = imported_fn_count + defined_fn_count;
+
+    let mut module = Module::new();
+    let mut code = CodeSection::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::FunctionSection(reader) => {
+                let mut functions = FunctionSection::new();
+                for ty in reader {
+                    functions.function(ty?);
+                }
+                for (_, ty, _) in &added {
+                    functions.function(*ty);
+                }
+                module.section(&functions);
+            }
+            Payload::ExportSection(reader) => {
+                let mut exports = ExportSection::new();
+                for export in reader {
+                    let export = export?;
+                    if methods.iter().any(|method| method == export.name) {
+                        bail!("Export `{}` already exists in module", export.name);
+                    }
+                    if keep(export.name) {
+                        exports.export(
+                            export.name,
+                            RoundtripReencoder.export_kind(export.kind)?,
+                            export.index,
+                        );
+                    }
+                }
+                for (index, (method, _, _)) in added.iter().enumerate() {
+                    exports.export(method, ExportKind::Func, first_added_fn + index as u32);
+                }
+                module.section(&exports);
+            }
+            Payload::CodeSectionEntry(body) => {
+                code.raw(&wasm[body.range()]);
+                if code.len() == defined_fn_count {
+                    for (_, _, function) in &added {
+                        code.function(function);
+                    }
+                    module.section(&code);
+                }
+            }
+            payload => {
+                if let Payload::CodeSectionStart { .. } = payload {
+                    continue;
+                }
+                if let Some((id, range)) = payload.as_section() {
+                    module.section(&RawSection {
+                        id,
+                        data: &wasm[range],
+                    });
+                }
+            }
+        }
+    }
+    Ok(module.finish())
+}
@@ -0,0 +1,245 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+
+/// A pure-Ruby gem unpacked from the gem cache.
+#[derive(Debug)]
+pub struct Gem {
+    /// Path of the `.gem` file the gem was unpacked from.
+    pub archive: PathBuf,
+    /// Directories of the gem to add to `$LOAD_PATH`.
+    pub require_paths: Vec<PathBuf>,
+    /// Feature to require when the gem is activated, if the gem has a file
+    /// named after itself like `Bundler.require` expects.
+    pub feature: Option<String>,
+}
+
+/// Unpacks the gems locked in `lockfile` from the `.gem` files in `cache`
+/// into `destination`.
+///
+/// The gems are returned in dependency order, so each gem comes after the
+/// gems it depends on.
+pub fn unpack(lockfile: &Path, cache: &Path, destination: &Path) -> Result<Vec<Gem>> {
+    let lockfile_contents = fs::read_to_string(lockfile)
+        .with_context(|| format!("Could not read {}", lockfile.display()))?;
+    let specs = parse_lockfile(&lockfile_contents)?;
+    dependency_order(&specs)
+        .into_iter()
+        .map(|spec| unpack_gem(spec, cache, destination))
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+struct Spec<'a> {
+    name: &'a str,
+    /// Version, including the platform for platform-specific gems.
+    version: &'a str,
+    dependencies: Vec<&'a str>,
+}
+
+/// Parses the specs of the `GEM` sections of a `Gemfile.lock`, keyed by name.
+fn parse_lockfile(lockfile: &str) -> Result<BTreeMap<&str, Spec<'_>>> {
+    let mut specs = BTreeMap::new();
+    let mut section = "";
+    let mut current: Option<Spec> = None;
+    for line in lockfile.lines() {
+        if !line.starts_with(' ') {
+            section = line.trim();
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+        let (name, version) = match line.trim().split_once(" (") {
+            Some((name, version)) => (name, version.trim_end_matches(')')),
+            None => (line.trim(), ""),
+        };
+        match (section, indent) {
+            ("GIT" | "PATH", 4) => {
+                bail!("The `{name}` gem comes from a {section} source, only gems from a gem server are supported")
+            }
+            ("GEM", 4) => {
+                if let Some(spec) = current.take() {
+                    insert_spec(&mut specs, spec);
+                }
+                current = Some(Spec {
+                    name,
+                    version,
+                    dependencies: vec![],
+                });
+            }
+            ("GEM", 6) => {
+                if let Some(spec) = current.as_mut() {
+                    spec.dependencies.push(name);
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(spec) = current {
+        insert_spec(&mut specs, spec);
+    }
+    Ok(specs)
+}
+
+/// Lockfiles list a spec per platform for gems that ship precompiled native
+/// extensions, prefers the platform-independent one.
+fn insert_spec<'a>(specs: &mut BTreeMap<&'a str, Spec<'a>>, spec: Spec<'a>) {
+    match specs.get(spec.name) {
+        Some(existing) if platform(existing).is_none() => {}
+        _ => {
+            specs.insert(spec.name, spec);
+        }
+    }
+}
+
+fn platform<'a>(spec: &Spec<'a>) -> Option<&'a str> {
+    spec.version.split_once('-').map(|(_, platform)| platform)
+}
+
+fn dependency_order<'a, 'b>(specs: &'b BTreeMap<&'a str, Spec<'a>>) -> Vec<&'b Spec<'a>> {
+    fn visit<'a, 'b>(
+        spec: &'b Spec<'a>,
+        specs: &'b BTreeMap<&'a str, Spec<'a>>,
+        visited: &mut HashSet<&'a str>,
+        ordered: &mut Vec<&'b Spec<'a>>,
+    ) {
+        if !visited.insert(spec.name) {
+            return;
+        }
+        for dependency in &spec.dependencies {
+            // Dependencies missing from the specs are default gems, which
+            // are part of the standard library.
+            if let Some(dependency) = specs.get(dependency) {
+                visit(dependency, specs, visited, ordered);
+            }
+        }
+        ordered.push(spec);
+    }
+
+    let mut visited = HashSet::new();
+    let mut ordered = vec![];
+    for spec in specs.values() {
+        visit(spec, specs, &mut visited, &mut ordered);
+    }
+    ordered
+}
+
+fn unpack_gem(spec: &Spec, cache: &Path, destination: &Path) -> Result<Gem> {
+    let Spec { name, version, .. } = spec;
+    if platform(spec).is_some() {
+        bail!("The `{name}` gem is only locked with precompiled native extensions, which can't run inside the Ruby engine");
+    }
+    let gem_path = cache.join(format!("{name}-{version}.gem"));
+    let gem_file =
+        File::open(&gem_path).with_context(|| format!("Could not open {}", gem_path.display()))?;
+
+    let gem_dir = destination.join(format!("{name}-{version}"));
+    let mut metadata = None;
+    // `.gem` files are tar archives containing the gemspec as YAML and
+    // another archive with the gem's files.
+    for entry in tar::Archive::new(gem_file).entries()? {
+        let entry = entry?;
+        match entry.path()?.to_str() {
+            Some("metadata.gz") => {
+                let mut yaml = String::new();
+                GzDecoder::new(entry).read_to_string(&mut yaml)?;
+                metadata = Some(yaml);
+            }
+            Some("data.tar.gz") => {
+                tar::Archive::new(GzDecoder::new(entry)).unpack(&gem_dir)?;
+            }
+            _ => {}
+        }
+    }
+    let metadata = metadata.ok_or_else(|| anyhow!("{} has no metadata", gem_path.display()))?;
+
+    if !yaml_list(&metadata, "extensions").is_empty() {
+        bail!(
+            "The `{name}` gem contains a native extension, which can't run inside the Ruby engine"
+        );
+    }
+    let mut require_paths = yaml_list(&metadata, "require_paths");
+    if require_paths.is_empty() {
+        require_paths.push("lib".to_string());
+    }
+    let require_paths: Vec<_> = require_paths
+        .iter()
+        .map(|path| gem_dir.join(path))
+        .collect();
+
+    let feature = [name.to_string(), name.replace('-', "/")]
+        .into_iter()
+        .find(|feature| {
+            require_paths
+                .iter()
+                .any(|path| path.join(format!("{feature}.rb")).is_file())
+        });
+    Ok(Gem {
+        archive: gem_path,
+        require_paths,
+        feature,
+    })
+}
+
+/// Reads a top-level list of strings from a gemspec serialized as YAML.
+fn yaml_list(yaml: &str, key: &str) -> Vec<String> {
+    let mut lines = yaml.lines();
+    let key = format!("{key}:");
+    if lines.by_ref().all(|line| line.trim_end() != key) {
+        return vec![];
+    }
+    lines
+        .map_while(|line| line.strip_prefix("- "))
+        .map(|item| item.trim_matches(['"', '\'']).to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lockfile() -> Result<()> {
+        let lockfile = "\
+GEM
+  remote: https://rubygems.org/
+  specs:
+    greeting (1.0.0)
+      shout (~> 0.1)
+    nokogiri (1.15.0)
+      racc (~> 1.4)
+    nokogiri (1.15.0-x86_64-linux)
+      racc (~> 1.4)
+    shout (0.1.0)
+
+PLATFORMS
+  ruby
+
+DEPENDENCIES
+  greeting
+";
+        let specs = parse_lockfile(lockfile)?;
+        let ordered: Vec<_> = dependency_order(&specs)
+            .iter()
+            .map(|spec| (spec.name, spec.version))
+            .collect();
+        assert_eq!(
+            vec![
+                ("shout", "0.1.0"),
+                ("greeting", "1.0.0"),
+                ("nokogiri", "1.15.0")
+            ],
+            ordered
+        );
+
+        let yaml = "extensions: []\nrequire_paths:\n- lib\n- \"ext\"\nrubygems_version: 3.4.10\n";
+        assert!(yaml_list(yaml, "extensions").is_empty());
+        assert_eq!(vec!["lib", "ext"], yaml_list(yaml, "require_paths"));
+        Ok(())
+    }
+}
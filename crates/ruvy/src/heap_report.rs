@@ -0,0 +1,143 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::format_size;
+
+/// Number of classes listed by the text report, largest first.
+const LISTED_CLASSES: usize = 20;
+
+/// Ruby objects retained by the snapshot, as measured by the engine just
+/// before the snapshot was taken.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HeapReport {
+    /// Whether sizes are estimated from object slots and contents, because the
+    /// engine doesn't link the `objspace` extension.
+    pub estimated: bool,
+    /// Live objects by class.
+    pub classes: Vec<ClassUsage>,
+    /// What each step of the initialization retained, in the order they ran.
+    pub sources: Vec<SourceUsage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClassUsage {
+    pub name: String,
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+/// What loading a source retained.
+///
+/// The counts are differences between the live objects before and after the
+/// source was loaded, so they are negative if loading it freed more objects
+/// than it retained.
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceUsage {
+    /// `ruby` for what the VM retains on its own, `stdlib`, `gem`, `preload`,
+    /// `require` for files required by the Ruby input file, or `entry` for the
+    /// input file itself.
+    pub kind: String,
+    /// Name of the library or feature, or path of the file, as seen by the
+    /// engine.
+    pub name: String,
+    pub objects: i64,
+    pub bytes: i64,
+}
+
+/// Parses the report the engine wrote as tab-separated lines.
+pub(crate) fn parse(lines: &str) -> HeapReport {
+    let mut report = HeapReport::default();
+    for line in lines.lines() {
+        let fields: Vec<_> = line.split('\t').collect();
+        match fields[..] {
+            ["estimated", estimated] => report.estimated = estimated == "true",
+            ["class", name, objects, bytes] => report.classes.push(ClassUsage {
+                name: name.to_string(),
+                objects: objects.parse().unwrap_or_default(),
+                bytes: bytes.parse().unwrap_or_default(),
+            }),
+            ["source", kind, name, objects, bytes] => report.sources.push(SourceUsage {
+                kind: kind.to_string(),
+                name: name.to_string(),
+                objects: objects.parse().unwrap_or_default(),
+                bytes: bytes.parse().unwrap_or_default(),
+            }),
+            _ => {}
+        }
+    }
+    report
+        .classes
+        .sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.name.cmp(&b.name)));
+    report
+}
+
+impl fmt::Display for HeapReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let objects: u64 = self.classes.iter().map(|class| class.objects).sum();
+        let bytes: u64 = self.classes.iter().map(|class| class.bytes).sum();
+        writeln!(
+            f,
+            "Ruby heap: {objects} objects, {}{}",
+            format_size(bytes),
+            if self.estimated { " (estimated)" } else { "" }
+        )?;
+
+        writeln!(f, "\nBy class:")?;
+        for class in self.classes.iter().take(LISTED_CLASSES) {
+            writeln!(
+                f,
+                "  {:<32} {:>9} objects {:>12}",
+                class.name,
+                class.objects,
+                format_size(class.bytes)
+            )?;
+        }
+        if self.classes.len() > LISTED_CLASSES {
+            writeln!(f, "  ... {} more", self.classes.len() - LISTED_CLASSES)?;
+        }
+
+        write!(f, "\nBy source:")?;
+        for source in &self.sources {
+            let size = format_size(source.bytes.unsigned_abs());
+            write!(
+                f,
+                "\n  {:<8} {:>9} objects {:>12} {}",
+                source.kind,
+                source.objects,
+                if source.bytes < 0 {
+                    format!("-{size}")
+                } else {
+                    size
+                },
+                source.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let report = parse(
+            "estimated\ttrue\n\
+            class\tString\t10\t400\n\
+            class\tArray\t2\t1000\n\
+            source\tstdlib\tjson\t5\t-20\n",
+        );
+        assert!(report.estimated);
+        assert_eq!(
+            vec!["Array", "String"],
+            report
+                .classes
+                .iter()
+                .map(|class| class.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(-20, report.sources[0].bytes);
+    }
+}
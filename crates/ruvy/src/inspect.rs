@@ -0,0 +1,415 @@
+use std::fmt;
+
+use anyhow::Result;
+use serde::Serialize;
+use wasmparser::{DataKind, ExternalKind, Operator, Parser, Payload, TypeRef};
+
+use crate::{format_size, Metadata, Sizes, ENGINE};
+
+/// Number of data segments listed by the text report, largest first.
+const LISTED_DATA_SEGMENTS: usize = 10;
+
+/// What a module contains, as returned by [`inspect`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Inspection {
+    pub sizes: Sizes,
+    /// Sizes of the [`ENGINE`] the module was presumably built from, to tell
+    /// what the snapshot added. `None` if the engine isn't available.
+    pub engine_sizes: Option<Sizes>,
+    pub imports: Vec<Import>,
+    pub exports: Vec<Export>,
+    pub memories: Vec<Memory>,
+    pub data_segments: Vec<DataSegment>,
+    pub custom_sections: Vec<CustomSection>,
+    /// `None` if the module has no `ruvy-metadata` section, or if it can't
+    /// be read, which is reported in [`Self::warnings`].
+    pub metadata: Option<Metadata>,
+    /// Problems with the module that didn't stop the inspection.
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub kind: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Export {
+    pub name: String,
+    pub kind: &'static str,
+}
+
+/// Limits of a linear memory, in 64 KiB pages.
+#[derive(Debug, Clone, Serialize)]
+pub struct Memory {
+    pub imported: bool,
+    pub initial: u64,
+    pub maximum: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DataSegment {
+    /// Offset the segment is copied to, `None` for passive segments or
+    /// offsets that aren't constants.
+    pub offset: Option<u64>,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CustomSection {
+    pub name: String,
+    pub size: usize,
+}
+
+/// Lists the imports, exports, memories, data segments and custom sections
+/// of `wasm`, along with its ruvy metadata.
+pub fn inspect(wasm: &[u8]) -> Result<Inspection> {
+    let mut inspection = Inspection {
+        sizes: Sizes::of(wasm)?,
+        engine_sizes: if ENGINE.is_empty() {
+            None
+        } else {
+            Some(Sizes::of(ENGINE)?)
+        },
+        imports: vec![],
+        exports: vec![],
+        memories: vec![],
+        data_segments: vec![],
+        custom_sections: vec![],
+        metadata: None,
+        warnings: vec![],
+    };
+    // Modules built by other versions of ruvy may have metadata this one
+    // can't read, which shouldn't keep the rest of the module from being
+    // inspected.
+    match Metadata::read(wasm) {
+        Ok(metadata) => inspection.metadata = metadata,
+        Err(err) => inspection.warnings.push(format!("{err:#}")),
+    }
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    if let TypeRef::Memory(ty) = import.ty {
+                        inspection.memories.push(Memory {
+                            imported: true,
+                            initial: ty.initial,
+                            maximum: ty.maximum,
+                        });
+                    }
+                    inspection.imports.push(Import {
+                        module: import.module.to_string(),
+                        name: import.name.to_string(),
+                        kind: match import.ty {
+                            TypeRef::Func(_) | TypeRef::FuncExact(_) => "func",
+                            TypeRef::Table(_) => "table",
+                            TypeRef::Memory(_) => "memory",
+                            TypeRef::Global(_) => "global",
+                            TypeRef::Tag(_) => "tag",
+                        },
+                    });
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for ty in reader {
+                    let ty = ty?;
+                    inspection.memories.push(Memory {
+                        imported: false,
+                        initial: ty.initial,
+                        maximum: ty.maximum,
+                    });
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    inspection.exports.push(Export {
+                        name: export.name.to_string(),
+                        kind: match export.kind {
+                            ExternalKind::Func | ExternalKind::FuncExact => "func",
+                            ExternalKind::Table => "table",
+                            ExternalKind::Memory => "memory",
+                            ExternalKind::Global => "global",
+                            ExternalKind::Tag => "tag",
+                        },
+                    });
+                }
+            }
+            Payload::DataSection(reader) => {
+                for data in reader {
+                    let data = data?;
+                    let offset = match data.kind {
+                        DataKind::Active { offset_expr, .. } => {
+                            match offset_expr.get_operators_reader().read()? {
+                                Operator::I32Const { value } => Some(value as u32 as u64),
+                                Operator::I64Const { value } => Some(value as u64),
+                                _ => None,
+                            }
+                        }
+                        DataKind::Passive => None,
+                    };
+                    inspection.data_segments.push(DataSegment {
+                        offset,
+                        size: data.data.len(),
+                    });
+                }
+            }
+            Payload::CustomSection(reader) => inspection.custom_sections.push(CustomSection {
+                name: reader.name().to_string(),
+                size: reader.data().len(),
+            }),
+            _ => {}
+        }
+    }
+    Ok(inspection)
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.engine_sizes {
+            Some(engine) => {
+                writeln!(f, "Sizes:   {:>12} {:>12}", "module", "engine")?;
+                let rows = [
+                    ("code", self.sizes.code, engine.code),
+                    ("data", self.sizes.data, engine.data),
+                    ("custom", self.sizes.custom, engine.custom),
+                    ("total", self.sizes.total, engine.total),
+                ];
+                for (name, module, engine) in rows {
+                    writeln!(
+                        f,
+                        "  {name:<6} {:>12} {:>12}",
+                        format_size(module as u64),
+                        format_size(engine as u64)
+                    )?;
+                }
+            }
+            None => writeln!(f, "Size: {}", format_size(self.sizes.total as u64))?,
+        }
+
+        writeln!(f, "\nImports:")?;
+        for import in &self.imports {
+            writeln!(f, "  {} {}::{}", import.kind, import.module, import.name)?;
+        }
+        writeln!(f, "\nExports:")?;
+        for export in &self.exports {
+            writeln!(f, "  {} {}", export.kind, export.name)?;
+        }
+
+        writeln!(f, "\nMemories:")?;
+        for memory in &self.memories {
+            let maximum = match memory.maximum {
+                Some(maximum) => format!("{maximum} pages"),
+                None => "none".to_string(),
+            };
+            writeln!(
+                f,
+                "  {}initial {} pages ({}), maximum {maximum}",
+                if memory.imported { "imported, " } else { "" },
+                memory.initial,
+                format_size(memory.initial * 64 * 1024),
+            )?;
+        }
+
+        let data_size: usize = self.data_segments.iter().map(|segment| segment.size).sum();
+        writeln!(
+            f,
+            "\nData segments: {}, {}",
+            self.data_segments.len(),
+            format_size(data_size as u64)
+        )?;
+        let mut largest: Vec<_> = self.data_segments.iter().collect();
+        largest.sort_by_key(|segment| std::cmp::Reverse(segment.size));
+        for segment in largest.iter().take(LISTED_DATA_SEGMENTS) {
+            let offset = match segment.offset {
+                Some(offset) => format!("{offset:#010x}"),
+                None => "passive".to_string(),
+            };
+            writeln!(f, "  {offset:>10} {:>12}", format_size(segment.size as u64))?;
+        }
+        if largest.len() > LISTED_DATA_SEGMENTS {
+            writeln!(f, "  ... {} more", largest.len() - LISTED_DATA_SEGMENTS)?;
+        }
+
+        writeln!(f, "\nCustom sections:")?;
+        for section in &self.custom_sections {
+            writeln!(
+                f,
+                "  {:<24} {:>12}",
+                section.name,
+                format_size(section.size as u64)
+            )?;
+        }
+
+        for warning in &self.warnings {
+            write!(f, "\nWarning: {warning}")?;
+        }
+        let Some(metadata) = &self.metadata else {
+            if !self.warnings.is_empty() {
+                return Ok(());
+            }
+            return write!(f, "\nNo ruvy metadata, the module was not built by ruvy");
+        };
+        writeln!(f, "\nBuilt by ruvy {}", metadata.ruvy_version)?;
+        writeln!(
+            f,
+            "  ruby.wasm {} (Ruby {}, {} profile), WASI SDK {}",
+            metadata.ruby_wasm_version,
+            metadata.ruby_wasm_ruby_version,
+            metadata.ruby_wasm_profile,
+            metadata.wasi_sdk_version
+        )?;
+        let options = &metadata.options;
+        writeln!(
+            f,
+            "  target {}, I/O format {}, dynamic {}, optimized {}, reproducible {}",
+            options.target,
+            options.io_format,
+            options.dynamic,
+            options.optimize,
+            options.reproducible
+        )?;
+        for (name, values) in [
+            ("stdlib", &options.stdlib),
+            ("load paths", &options.load_paths),
+            ("exports", &options.exports),
+        ] {
+            if !values.is_empty() {
+                writeln!(f, "  {name}: {}", values.join(", "))?;
+            }
+        }
+        write!(f, "\nSources:")?;
+        write!(
+            f,
+            "\n  entry   {} {}",
+            short_hash(&metadata.entry_sha256),
+            metadata
+                .entry_file
+                .as_deref()
+                .unwrap_or("(not from a file)")
+        )?;
+        for file in &metadata.files {
+            write!(
+                f,
+                "\n  {:<7} {} {}",
+                file.kind.to_string(),
+                short_hash(&file.sha256),
+                file.path
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Shortens a SHA-256 hash from the metadata for display. The metadata comes
+/// from the module, so the hash may not be one.
+fn short_hash(hash: &str) -> &str {
+    hash.get(..12).unwrap_or(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_encoder::Section;
+
+    use super::*;
+    use crate::{
+        metadata::{BuildOptions, SECTION_NAME},
+        IoFormat, Target,
+    };
+
+    /// A module with an imported memory, an exported function and two data
+    /// segments, optionally followed by a `ruvy-metadata` section.
+    fn module(metadata: Option<&[u8]>) -> Result<Vec<u8>> {
+        let mut wasm = wat::parse_str(
+            r#"(module
+                (import "env" "memory" (memory 1 2))
+                (func (export "run"))
+                (data (i32.const 16) "abcd")
+                (data "ef"))"#,
+        )?;
+        if let Some(data) = metadata {
+            wasm_encoder::CustomSection {
+                name: SECTION_NAME.into(),
+                data: data.into(),
+            }
+            .append_to(&mut wasm);
+        }
+        Ok(wasm)
+    }
+
+    #[test]
+    fn test_inspect() -> Result<()> {
+        let metadata = Metadata::new(
+            "puts 1",
+            None,
+            &[],
+            &[],
+            BuildOptions {
+                load_paths: vec![],
+                stdlib: vec![],
+                exports: vec!["run".to_string()],
+                dynamic: false,
+                target: Target::Wasi,
+                io_format: IoFormat::Raw,
+                optimize: false,
+                reproducible: false,
+            },
+        )?;
+        let inspection = inspect(&module(Some(&serde_json::to_vec(&metadata)?))?)?;
+        assert_eq!(
+            vec![("env", "memory", "memory")],
+            inspection
+                .imports
+                .iter()
+                .map(|import| (import.module.as_str(), import.name.as_str(), import.kind))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![("run", "func")],
+            inspection
+                .exports
+                .iter()
+                .map(|export| (export.name.as_str(), export.kind))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, inspection.memories.len());
+        assert!(inspection.memories[0].imported);
+        assert_eq!(1, inspection.memories[0].initial);
+        assert_eq!(Some(2), inspection.memories[0].maximum);
+        assert_eq!(
+            vec![(Some(16), 4), (None, 2)],
+            inspection
+                .data_segments
+                .iter()
+                .map(|segment| (segment.offset, segment.size))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(metadata), inspection.metadata);
+        assert!(inspection.warnings.is_empty());
+
+        let inspection = inspect(&module(None)?)?;
+        assert_eq!(None, inspection.metadata);
+        assert!(inspection.warnings.is_empty());
+        assert!(inspection.to_string().contains("No ruvy metadata"));
+
+        let inspection = inspect(&module(Some(b"{\"ruvy_version\":"))?)?;
+        assert_eq!(1, inspection.exports.len());
+        assert_eq!(None, inspection.metadata);
+        assert_eq!(1, inspection.warnings.len());
+        assert!(inspection.warnings[0].starts_with("Invalid `ruvy-metadata` section"));
+        assert!(inspection
+            .to_string()
+            .contains("Warning: Invalid `ruvy-metadata` section"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_hash() {
+        assert_eq!("0123456789ab", short_hash("0123456789abcdef"));
+        assert_eq!("abc", short_hash("abc"));
+        assert_eq!("aéééééé", short_hash("aéééééé"));
+    }
+}
@@ -0,0 +1,637 @@
+//! Compiles Ruby code into WebAssembly modules.
+//!
+//! The Ruby engine is initialized with the code and snapshotted, so the
+//! modules start running the code right away:
+//!
+//! ```no_run
+//! # async fn build() -> anyhow::Result<()> {
+//! let output = ruvy::Compiler::from_file("main.rb")?
+//!     .preload("prelude")
+//!     .stdlib("set")
+//!     .compile()
+//!     .await?;
+//! std::fs::write("index.wasm", output.wasm)?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod cache;
+mod diagnostics;
+mod dynamic;
+mod exports;
+mod gems;
+mod heap_report;
+mod inspect;
+mod metadata;
+mod optimize;
+mod reproducible;
+mod run;
+mod shopify_function;
+mod stdlib;
+mod vm;
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use cache::Cache;
+use gems::Gem;
+use serde::{Deserialize, Serialize};
+
+pub use diagnostics::{CompileError, Diagnostic, Location, Severity};
+pub use dynamic::ENGINE_IMPORT_NAMESPACE;
+pub use heap_report::{ClassUsage, HeapReport, SourceUsage};
+pub use inspect::{inspect, CustomSection, DataSegment, Export, Import, Inspection, Memory};
+pub use metadata::{BuildOptions, Metadata, SourceFile, SourceKind};
+pub use optimize::{SizeReport, Sizes};
+pub use reproducible::{Difference, Verification};
+pub use run::{execute, Execution, OutOfFuel, Report, Usage};
+pub use shopify_function::Limits;
+use vm::VmConfig;
+
+/// The Ruby engine modules are compiled from by default.
+pub const ENGINE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/engine.wasm"));
+
+/// Platform to build a module for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Target {
+    /// A WASI command running the script from `_start`.
+    #[default]
+    Wasi,
+    /// A Shopify Function whose `run` export calls the script's `run` method
+    /// with the input parsed from JSON on stdin, and writes the method's
+    /// result as JSON on stdout.
+    ///
+    /// Compiling fails if the module exceeds the [`Limits`].
+    ShopifyFunction,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Target::Wasi => "wasi",
+            Target::ShopifyFunction => "shopify-function",
+        })
+    }
+}
+
+/// Format of the data `Ruvy.input` reads from stdin and `Ruvy.output` writes
+/// to stdout.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoFormat {
+    /// Binary strings, read and written as is.
+    #[default]
+    Raw,
+    /// JSON documents, parsed and generated like `JSON.parse` and
+    /// `JSON.generate` do. Requires the engine's `json` feature.
+    Json,
+    /// MessagePack objects, unpacked and packed like `MessagePack.unpack` and
+    /// `MessagePack.pack` do. Requires the engine's `msgpack` feature.
+    Msgpack,
+}
+
+impl fmt::Display for IoFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IoFormat::Raw => "raw",
+            IoFormat::Json => "json",
+            IoFormat::Msgpack => "msgpack",
+        })
+    }
+}
+
+/// Builds a Wasm module from Ruby code.
+#[derive(Debug, Clone)]
+pub struct Compiler {
+    source: String,
+    input_path: Option<PathBuf>,
+    preload_paths: Vec<PathBuf>,
+    load_paths: Vec<PathBuf>,
+    stdlib: Vec<String>,
+    gemfile: Option<PathBuf>,
+    gem_cache: Option<PathBuf>,
+    exports: Vec<String>,
+    dynamic: bool,
+    target: Target,
+    limits: Limits,
+    io_format: IoFormat,
+    optimize: bool,
+    heap_report: bool,
+    reproducible: bool,
+    cache_dir: Option<PathBuf>,
+    engine: Option<Vec<u8>>,
+}
+
+/// A module built by a [`Compiler`].
+#[derive(Debug)]
+pub struct Output {
+    pub wasm: Vec<u8>,
+    /// What Ruby printed on stdout while the module was built.
+    pub stdout: Vec<u8>,
+    /// What Ruby printed on stderr while the module was built, like warnings.
+    pub diagnostics: Vec<Diagnostic>,
+    /// Sizes of the module before and after optimizing it, if it was.
+    pub size_report: Option<SizeReport>,
+    /// Ruby objects retained by the snapshot, if a report was requested.
+    pub heap_report: Option<HeapReport>,
+    /// Files Ruby loaded with `require` while the module was built.
+    pub loaded_files: Vec<PathBuf>,
+}
+
+impl Compiler {
+    /// Creates a compiler for the Ruby code in `source`.
+    pub fn new(source: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            input_path: None,
+            preload_paths: vec![],
+            load_paths: vec![],
+            stdlib: vec![],
+            gemfile: None,
+            gem_cache: None,
+            exports: vec![],
+            dynamic: false,
+            target: Target::default(),
+            limits: Limits::default(),
+            io_format: IoFormat::default(),
+            optimize: false,
+            heap_report: false,
+            reproducible: false,
+            cache_dir: None,
+            engine: None,
+        }
+    }
+
+    /// Creates a compiler for the Ruby file at `path`, see [`Self::input_path`].
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Could not read Ruby file {}", path.display()))?;
+        let mut compiler = Self::new(source);
+        compiler.input_path(path);
+        Ok(compiler)
+    }
+
+    /// Sets the path the source was read from, which backtraces and
+    /// `require_relative` calls refer to.
+    pub fn input_path(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.input_path = Some(path.into());
+        self
+    }
+
+    /// Adds a Ruby file, or a directory of Ruby files, to evaluate before the
+    /// source.
+    ///
+    /// Directories are searched recursively for `.rb` files, which are loaded
+    /// in path order unless the directory contains a `ruvy-preload.txt`
+    /// manifest listing the files to load.
+    pub fn preload(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.preload_paths.push(path.into());
+        self
+    }
+
+    /// Adds a directory to `$LOAD_PATH` when resolving `require` calls.
+    pub fn load_path(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.load_paths.push(dir.into());
+        self
+    }
+
+    /// Adds a library of the Ruby standard library to load, e.g. `json`.
+    pub fn stdlib(&mut self, library: impl Into<String>) -> &mut Self {
+        self.stdlib.push(library.into());
+        self
+    }
+
+    /// Sets the path of a `Gemfile.lock` whose gems are loaded.
+    ///
+    /// Only pure-Ruby gems from a gem server are supported.
+    pub fn gemfile(&mut self, lockfile: impl Into<PathBuf>) -> &mut Self {
+        self.gemfile = Some(lockfile.into());
+        self
+    }
+
+    /// Sets the directory containing the `.gem` files of the gems locked in
+    /// the [`Self::gemfile`]. Defaults to `vendor/cache` next to the lockfile.
+    pub fn gem_cache(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.gem_cache = Some(dir.into());
+        self
+    }
+
+    /// Exports the top-level Ruby method `method` as a Wasm function of the
+    /// same name.
+    ///
+    /// When used, the module does not export `_start`.
+    pub fn export(&mut self, method: impl Into<String>) -> &mut Self {
+        self.exports.push(method.into());
+        self
+    }
+
+    /// Emits a small module that imports the Ruby engine instead of embedding
+    /// it, see [`emit_engine`].
+    pub fn dynamic(&mut self, dynamic: bool) -> &mut Self {
+        self.dynamic = dynamic;
+        self
+    }
+
+    pub fn target(&mut self, target: Target) -> &mut Self {
+        self.target = target;
+        self
+    }
+
+    /// Sets the limits checked for [`Target::ShopifyFunction`].
+    pub fn limits(&mut self, limits: Limits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets the format of the data `Ruvy.input` reads and `Ruvy.output`
+    /// writes.
+    pub fn io_format(&mut self, io_format: IoFormat) -> &mut Self {
+        self.io_format = io_format;
+        self
+    }
+
+    /// Shrinks the module after it is built by stripping debug information,
+    /// code that can only run during initialization and redundant data.
+    pub fn optimize(&mut self, optimize: bool) -> &mut Self {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Measures the Ruby objects retained by the snapshot just before it is
+    /// taken, by class and by the library or file that loaded them, see
+    /// [`Output::heap_report`].
+    ///
+    /// Measuring runs the garbage collector after each library and file, which
+    /// slows down the build.
+    pub fn heap_report(&mut self, heap_report: bool) -> &mut Self {
+        self.heap_report = heap_report;
+        self
+    }
+
+    /// Makes building the same inputs produce the same module, wherever the
+    /// build runs.
+    ///
+    /// While the module is built, the Ruby engine gets random numbers seeded
+    /// from the source, a clock stopped at `SOURCE_DATE_EPOCH` or the Unix
+    /// epoch, and `Dir.entries`, `Dir.children`, `Dir.each_child` and
+    /// `Dir.foreach` return entries sorted by name.
+    pub fn reproducible(&mut self, reproducible: bool) -> &mut Self {
+        self.reproducible = reproducible;
+        self
+    }
+
+    /// Reuses the module built before from the same inputs if there is one in
+    /// `dir`, and stores the module built otherwise.
+    ///
+    /// Modules are looked up by the hashes of the engine, the source, the
+    /// preloaded files and gems and the options, and reused if none of the
+    /// files Ruby loaded with `require` changed. Builds with a
+    /// [`Self::heap_report`] aren't cached.
+    pub fn cache(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the Ruby engine to compile the module from instead of [`ENGINE`].
+    pub fn engine(&mut self, engine: impl Into<Vec<u8>>) -> &mut Self {
+        self.engine = Some(engine.into());
+        self
+    }
+
+    /// Builds the module.
+    ///
+    /// Fails with a [`CompileError`] if evaluating the code fails, e.g.
+    /// because of a syntax error.
+    pub async fn compile(&self) -> Result<Output> {
+        for (index, method) in self.exports.iter().enumerate() {
+            check_method_name(method)?;
+            // Wasm modules can't have two exports with the same name.
+            if self.exports[..index].contains(method) {
+                bail!("`{method}` is exported more than once");
+            }
+        }
+        if self.dynamic
+            && (!self.preload_paths.is_empty()
+                || !self.load_paths.is_empty()
+                || !self.stdlib.is_empty()
+                || self.gemfile.is_some())
+        {
+            bail!("Preloading files, load paths, the standard library and gems are not supported in dynamic mode");
+        }
+        if self.dynamic && self.heap_report {
+            bail!("Heap reports are not supported in dynamic mode");
+        }
+        if self.dynamic && self.io_format != IoFormat::Raw {
+            bail!("I/O formats other than raw are not supported in dynamic mode");
+        }
+        let build_dir = tempfile::tempdir()?;
+        let gems = match &self.gemfile {
+            Some(gemfile) => {
+                let gem_cache = self.gem_cache.clone().unwrap_or_else(|| {
+                    gemfile
+                        .parent()
+                        .unwrap_or(Path::new(""))
+                        .join("vendor/cache")
+                });
+                gems::unpack(gemfile, &gem_cache, &build_dir.path().join("gems"))?
+            }
+            None => vec![],
+        };
+        // Paths depending on where the build runs are left out of the
+        // metadata, so the same inputs produce the same module.
+        let input_dir = match self.input_path.as_deref().and_then(Path::parent) {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let metadata = Metadata::new(
+            &self.source,
+            self.input_path.as_deref(),
+            &self.preload_paths,
+            &gems,
+            BuildOptions {
+                load_paths: self
+                    .load_paths
+                    .iter()
+                    .map(|path| metadata::relative_path(path, input_dir))
+                    .collect::<Result<_>>()?,
+                stdlib: self.stdlib.clone(),
+                exports: self.exports.clone(),
+                dynamic: self.dynamic,
+                target: self.target,
+                io_format: self.io_format,
+                optimize: self.optimize,
+                reproducible: self.reproducible,
+            },
+        )?;
+
+        let cache = match &self.cache_dir {
+            Some(dir) if !self.heap_report => {
+                let paths: Vec<_> = self
+                    .input_path
+                    .iter()
+                    .chain(&self.load_paths)
+                    .chain(&self.preload_paths)
+                    .map(PathBuf::as_path)
+                    .collect();
+                let key = cache::key(&metadata, self.engine_or_default(), &self.limits, &paths)?;
+                Some((Cache::new(dir), key))
+            }
+            _ => None,
+        };
+        if let Some(output) = cache.as_ref().and_then(|(cache, key)| cache.get(key)) {
+            return Ok(output);
+        }
+
+        let mut output = if self.dynamic {
+            let (methods, export_names) = self.entrypoints()?;
+            let source = match self.target {
+                Target::Wasi => self.source.clone(),
+                // Appended rather than prepended so line numbers in
+                // backtraces match the script.
+                Target::ShopifyFunction => {
+                    format!("{}\n{}", self.source, shopify_function::ENTRYPOINT)
+                }
+            };
+            Output {
+                wasm: dynamic::generate(&source, &methods, &export_names),
+                stdout: vec![],
+                diagnostics: vec![],
+                size_report: None,
+                heap_report: None,
+                loaded_files: vec![],
+            }
+        } else {
+            self.compile_static(build_dir.path(), &gems).await?
+        };
+
+        if self.optimize {
+            let (wasm, size_report) = optimize::optimize(&output.wasm)?;
+            output.wasm = wasm;
+            output.size_report = Some(size_report);
+        }
+        metadata.append_to(&mut output.wasm)?;
+        if self.target == Target::ShopifyFunction {
+            shopify_function::check(&output.wasm, &self.limits)?;
+        }
+        if let Some((cache, key)) = &cache {
+            cache.put(key, &output)?;
+        }
+        Ok(output)
+    }
+
+    /// Builds the module twice in [`Self::reproducible`] mode and compares
+    /// the results.
+    pub async fn verify_reproducible(&self) -> Result<Verification> {
+        let mut compiler = self.clone();
+        compiler.reproducible(true);
+        // Both builds have to run for their results to be compared.
+        compiler.cache_dir = None;
+        let first = compiler.compile().await?.wasm;
+        let second = compiler.compile().await?.wasm;
+        Ok(Verification {
+            sha256: metadata::sha256(&first),
+            difference: reproducible::first_difference(&first, &second)?,
+        })
+    }
+
+    async fn compile_static(&self, build_dir: &Path, gems: &[Gem]) -> Result<Output> {
+        let mut preload_paths = self.preload_paths.clone();
+        let (methods, export_names) = self.entrypoints()?;
+        if self.target == Target::ShopifyFunction {
+            let entrypoint_path = build_dir.join("shopify_function.rb");
+            fs::write(&entrypoint_path, shopify_function::ENTRYPOINT)?;
+            preload_paths.push(entrypoint_path);
+        }
+
+        let stdlib_paths = if self.stdlib.is_empty() {
+            vec![]
+        } else {
+            stdlib::unpack(&build_dir.join("stdlib"))?
+        };
+        let vm_config = VmConfig {
+            input_path: self.input_path.as_deref(),
+            preload_paths: &preload_paths,
+            load_paths: &self.load_paths,
+            stdlib: &self.stdlib,
+            stdlib_paths: &stdlib_paths,
+            gems,
+            exports: &methods,
+            io_format: self.io_format,
+            heap_report: self.heap_report,
+            reproducible: self.reproducible,
+        };
+        let snapshot = vm::wizen(self.engine_or_default(), &self.source, &vm_config).await?;
+        // Loaded files have canonical paths.
+        let build_dir = build_dir.canonicalize()?;
+        let wasm = if export_names.is_empty() {
+            snapshot.wasm
+        } else {
+            exports::add_ruby_exports(&snapshot.wasm, &export_names)?
+        };
+        Ok(Output {
+            wasm,
+            stdout: snapshot.stdout,
+            diagnostics: snapshot.diagnostics,
+            size_report: None,
+            heap_report: snapshot.heap_report,
+            // The build directory, where the standard library and gems are
+            // unpacked, is deleted after the build, so its files can't be
+            // checked when the cache is read.
+            loaded_files: snapshot
+                .loaded_files
+                .into_iter()
+                .filter(|path| !path.starts_with(&build_dir))
+                .collect(),
+        })
+    }
+
+    /// Returns the methods called by the exports, and the names of the
+    /// exports.
+    fn entrypoints(&self) -> Result<(Vec<String>, Vec<String>)> {
+        match self.target {
+            Target::Wasi => Ok((self.exports.clone(), self.exports.clone())),
+            Target::ShopifyFunction => {
+                if !self.exports.is_empty() {
+                    bail!("Exports are not supported by the Shopify Function target");
+                }
+                Ok((
+                    vec![shopify_function::ENTRYPOINT_METHOD.to_string()],
+                    vec![shopify_function::EXPORT.to_string()],
+                ))
+            }
+        }
+    }
+
+    fn engine_or_default(&self) -> &[u8] {
+        self.engine.as_deref().unwrap_or(ENGINE)
+    }
+}
+
+/// Initializes `engine`, usually [`ENGINE`], into the engine module imported
+/// by modules compiled in dynamic mode.
+pub async fn emit_engine(engine: &[u8]) -> Result<Vec<u8>> {
+    let snapshot = vm::wizen(engine, "", &VmConfig::default()).await?;
+    // The engine is linked as a reactor by hosts, so it can't be a command.
+    exports::remove(&snapshot.wasm, "_start")
+}
+
+/// Keywords and pseudo-variables, which can't be defined and called as plain
+/// top-level methods.
+const RUBY_KEYWORDS: &[&str] = &[
+    "__ENCODING__",
+    "__FILE__",
+    "__LINE__",
+    "__END__",
+    "__method__",
+    "__dir__",
+    "__callee__",
+    "alias",
+    "and",
+    "begin",
+    "break",
+    "case",
+    "class",
+    "def",
+    "defined?",
+    "do",
+    "else",
+    "elsif",
+    "end",
+    "ensure",
+    "false",
+    "for",
+    "if",
+    "in",
+    "module",
+    "next",
+    "nil",
+    "not",
+    "or",
+    "redo",
+    "rescue",
+    "retry",
+    "return",
+    "self",
+    "super",
+    "then",
+    "true",
+    "undef",
+    "unless",
+    "until",
+    "when",
+    "while",
+    "yield",
+];
+
+/// Exports of the engine and of the modules ruvy builds.
+const RESERVED_EXPORTS: &[&str] = &[
+    "_start",
+    "memory",
+    "canonical_abi_realloc",
+    "ruvy_call_export",
+    "ruvy_eval",
+    "ruvy_invoke",
+];
+
+/// Only accepts names of top-level Ruby methods that can be exported, since the
+/// engine calls exports as methods of the top-level `self`.
+fn check_method_name(name: &str) -> Result<()> {
+    let valid_start = name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_');
+    let body = name.trim_end_matches(['?', '!']);
+    let valid_body =
+        body.len() + 1 >= name.len() && body.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_start || !valid_body || RUBY_KEYWORDS.contains(&name) {
+        bail!("`{name}` is not a valid Ruby method name");
+    }
+    if RESERVED_EXPORTS.contains(&name) {
+        bail!("`{name}` is reserved for the exports of the Ruby engine");
+    }
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{bytes} B")
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_method_name() {
+        for name in ["run", "_run", "valid?", "save!", "run2"] {
+            assert!(check_method_name(name).is_ok(), "{name}");
+        }
+        for name in [
+            "Run",
+            "2run",
+            "run?!",
+            "run-it",
+            "",
+            "nil",
+            "self",
+            "end",
+            "def",
+            "__method__",
+        ] {
+            assert!(check_method_name(name).is_err(), "{name}");
+        }
+        for name in ["_start", "memory", "ruvy_invoke"] {
+            assert!(check_method_name(name)
+                .unwrap_err()
+                .to_string()
+                .contains("reserved"));
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    fmt, fs, iter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wasm_encoder::{CustomSection, Section};
+use wasmparser::{Parser, Payload};
+
+use crate::{gems::Gem, IoFormat, Target};
+
+/// Name of the custom section containing the [`Metadata`] as JSON.
+pub const SECTION_NAME: &str = "ruvy-metadata";
+
+/// How a module was built, as recorded in its `ruvy-metadata` custom section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub ruvy_version: String,
+    /// Version of the ruby.wasm release the engine is built with, or `custom`
+    /// if it was built with `RUVY_WASM_SYS_RUBY_PATH`.
+    pub ruby_wasm_version: String,
+    /// Version of Ruby in the ruby.wasm release, e.g. `3_2`.
+    pub ruby_wasm_ruby_version: String,
+    /// Profile of the ruby.wasm release, e.g. `minimal`.
+    pub ruby_wasm_profile: String,
+    /// Version of the WASI SDK the engine is built with, or `custom` if it
+    /// was built with `RUVY_WASM_SYS_WASI_SDK_PATH`.
+    pub wasi_sdk_version: String,
+    /// File name of the Ruby input file, if the source was read from a file.
+    pub entry_file: Option<String>,
+    /// SHA-256 hash of the source.
+    pub entry_sha256: String,
+    /// Preloaded files and gems.
+    pub files: Vec<SourceFile>,
+    pub options: BuildOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceFile {
+    pub kind: SourceKind,
+    /// Path of the file relative to the directory containing the preload path
+    /// it was found in, or file name of the `.gem` file.
+    pub path: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+    Preload,
+    Gem,
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SourceKind::Preload => "preload",
+            SourceKind::Gem => "gem",
+        })
+    }
+}
+
+/// Options the module was built with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildOptions {
+    /// Directories added to `$LOAD_PATH`, relative to the directory of the
+    /// input file, see [`relative_path`].
+    pub load_paths: Vec<String>,
+    pub stdlib: Vec<String>,
+    pub exports: Vec<String>,
+    pub dynamic: bool,
+    pub target: Target,
+    #[serde(default)]
+    pub io_format: IoFormat,
+    pub optimize: bool,
+    #[serde(default)]
+    pub reproducible: bool,
+}
+
+impl Metadata {
+    /// Describes a build of `source`, read from `input_path` if any, with
+    /// the given preload paths, gems and options.
+    pub(crate) fn new(
+        source: &str,
+        input_path: Option<&Path>,
+        preload_paths: &[PathBuf],
+        gems: &[Gem],
+        options: BuildOptions,
+    ) -> Result<Self> {
+        let mut files = vec![];
+        for preload_path in preload_paths {
+            let root = preload_path.parent().unwrap_or(Path::new(""));
+            // The same files the engine preloads.
+            for file in ruvy_preload::files(preload_path)? {
+                let contents = fs::read(&file)
+                    .with_context(|| format!("Could not read {}", file.display()))?;
+                files.push(SourceFile {
+                    kind: SourceKind::Preload,
+                    path: file.strip_prefix(root)?.to_string_lossy().into_owned(),
+                    sha256: sha256(&contents),
+                });
+            }
+        }
+        for gem in gems {
+            let contents = fs::read(&gem.archive)
+                .with_context(|| format!("Could not read {}", gem.archive.display()))?;
+            files.push(SourceFile {
+                kind: SourceKind::Gem,
+                path: file_name(&gem.archive),
+                sha256: sha256(&contents),
+            });
+        }
+
+        Ok(Self {
+            ruvy_version: env!("CARGO_PKG_VERSION").to_string(),
+            ruby_wasm_version: env!("RUVY_RUBY_WASM_VERSION").to_string(),
+            ruby_wasm_ruby_version: env!("RUVY_RUBY_WASM_RUBY_VERSION").to_string(),
+            ruby_wasm_profile: env!("RUVY_RUBY_WASM_PROFILE").to_string(),
+            wasi_sdk_version: env!("RUVY_WASI_SDK_VERSION").to_string(),
+            entry_file: input_path.map(file_name),
+            entry_sha256: sha256(source.as_bytes()),
+            files,
+            options,
+        })
+    }
+
+    /// Reads the metadata of `wasm`, if it has a `ruvy-metadata` section.
+    pub fn read(wasm: &[u8]) -> Result<Option<Self>> {
+        for payload in Parser::new(0).parse_all(wasm) {
+            if let Payload::CustomSection(reader) = payload? {
+                if reader.name() == SECTION_NAME {
+                    let metadata = serde_json::from_slice(reader.data())
+                        .with_context(|| format!("Invalid `{SECTION_NAME}` section"))?;
+                    return Ok(Some(metadata));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Appends a `ruvy-metadata` section containing the metadata to `wasm`.
+    pub(crate) fn append_to(&self, wasm: &mut Vec<u8>) -> Result<()> {
+        let section = CustomSection {
+            name: SECTION_NAME.into(),
+            data: serde_json::to_vec(self)?.into(),
+        };
+        section.append_to(wasm);
+        Ok(())
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Returns the path of `path` relative to the directory `base`, with `/`
+/// separators, so it doesn't depend on where the build runs.
+pub(crate) fn relative_path(path: &Path, base: &Path) -> Result<String> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Could not find {}", path.display()))?;
+    let base = base
+        .canonicalize()
+        .with_context(|| format!("Could not find {}", base.display()))?;
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let parts: Vec<_> = iter::repeat_n("..".into(), base.components().count() - common)
+        .chain(
+            path.components()
+                .skip(common)
+                .map(|component| component.as_os_str().to_string_lossy()),
+        )
+        .collect();
+    if parts.is_empty() {
+        return Ok(".".to_string());
+    }
+    Ok(parts.join("/"))
+}
+
+pub(crate) fn sha256(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let options = BuildOptions {
+            load_paths: vec![],
+            stdlib: vec!["json".to_string()],
+            exports: vec![],
+            dynamic: false,
+            target: Target::ShopifyFunction,
+            io_format: IoFormat::Json,
+            optimize: true,
+            reproducible: false,
+        };
+        let metadata = Metadata::new("puts 1", Some(Path::new("app/main.rb")), &[], &[], options)?;
+        let mut wasm = wasm_encoder::Module::new().finish();
+        assert_eq!(None, Metadata::read(&wasm)?);
+        metadata.append_to(&mut wasm)?;
+        wasmparser::validate(&wasm)?;
+        assert_eq!(Some(metadata), Metadata::read(&wasm)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_relative_path() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let app = dir.path().join("app");
+        fs::create_dir_all(app.join("lib/ext"))?;
+        fs::create_dir_all(dir.path().join("shared"))?;
+        assert_eq!("lib/ext", relative_path(&app.join("lib/ext"), &app)?);
+        assert_eq!(
+            "../shared",
+            relative_path(&dir.path().join("shared"), &app)?
+        );
+        assert_eq!(".", relative_path(&app, &app)?);
+        Ok(())
+    }
+}
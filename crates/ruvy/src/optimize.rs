@@ -0,0 +1,399 @@
+use std::{collections::HashSet, fmt, ops::Range};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use wasm_encoder::{
+    reencode::{Reencode, RoundtripReencoder},
+    CodeSection, ConstExpr, DataCountSection, DataSection, ExportSection, Function, Module,
+    RawSection,
+};
+use wasmparser::{
+    DataKind, ElementItems, ExternalKind, FunctionBody, Operator, Parser, Payload, TypeRef,
+};
+
+use crate::format_size;
+
+/// The export Wizer calls to initialize the engine.
+const INIT_EXPORT: &str = "wizer-initialize";
+
+/// Custom sections that aren't needed to run the module.
+const STRIPPED_CUSTOM_SECTIONS: &[&str] = &["name", "producers"];
+
+/// Sizes in bytes of the parts of a module.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sizes {
+    pub code: usize,
+    pub data: usize,
+    pub custom: usize,
+    pub total: usize,
+}
+
+impl Sizes {
+    pub(crate) fn of(wasm: &[u8]) -> Result<Self> {
+        let mut sizes = Sizes {
+            total: wasm.len(),
+            ..Sizes::default()
+        };
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload? {
+                Payload::CodeSectionStart { size, .. } => sizes.code += size as usize,
+                Payload::DataSection(reader) => sizes.data += reader.range().len(),
+                Payload::CustomSection(reader) => sizes.custom += reader.range().len(),
+                _ => {}
+            }
+        }
+        Ok(sizes)
+    }
+}
+
+/// Sizes of a module before and after [`optimize`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SizeReport {
+    pub before: Sizes,
+    pub after: Sizes,
+}
+
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = [
+            ("code", self.before.code, self.after.code),
+            ("data", self.before.data, self.after.data),
+            ("custom", self.before.custom, self.after.custom),
+            ("total", self.before.total, self.after.total),
+        ];
+        writeln!(f, "{:<8} {:>12} {:>12}", "", "before", "after")?;
+        for (index, (name, before, after)) in rows.into_iter().enumerate() {
+            write!(
+                f,
+                "{name:<8} {:>12} {:>12}",
+                format_size(before as u64),
+                format_size(after as u64)
+            )?;
+            if index + 1 < rows.len() {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Shrinks a wizened module without changing its behavior.
+///
+/// - Drops the `name` and `producers` custom sections.
+/// - Removes the `wizer-initialize` export if Wizer kept it, and replaces the
+///   body of every function that can no longer be called with `unreachable`.
+///   Functions keep their indices, so tables and exports don't change.
+/// - Trims zeros from the edges of data segments and merges segments when the
+///   zeros between them take less space than a new segment. Memory starts
+///   zeroed, so this drops the trailing zero pages of the snapshot from the
+///   data. The memory's initial size is kept, since the allocator's heap
+///   spans all of it.
+pub fn optimize(wasm: &[u8]) -> Result<(Vec<u8>, SizeReport)> {
+    let live_functions = live_functions(wasm)?;
+    let data = merged_data(wasm)?;
+
+    let mut module = Module::new();
+    let mut code = CodeSection::new();
+    let mut defined_fn_count = 0;
+    let mut function_index = 0;
+    for payload in Parser::new(0).parse_all(wasm) {
+        let payload = payload?;
+        match &payload {
+            Payload::ImportSection(reader) => {
+                for import in reader.clone() {
+                    if matches!(import?.ty, TypeRef::Func(_)) {
+                        function_index += 1;
+                    }
+                }
+            }
+            Payload::FunctionSection(reader) => defined_fn_count = reader.count(),
+            Payload::CustomSection(reader) if STRIPPED_CUSTOM_SECTIONS.contains(&reader.name()) => {
+                continue;
+            }
+            Payload::ExportSection(reader) => {
+                let mut exports = ExportSection::new();
+                for export in reader.clone() {
+                    let export = export?;
+                    if export.name != INIT_EXPORT {
+                        exports.export(
+                            export.name,
+                            RoundtripReencoder.export_kind(export.kind)?,
+                            export.index,
+                        );
+                    }
+                }
+                module.section(&exports);
+                continue;
+            }
+            Payload::CodeSectionStart { .. } => continue,
+            Payload::CodeSectionEntry(body) => {
+                if live_functions.contains(&function_index) {
+                    code.raw(&wasm[body.range()]);
+                } else {
+                    let mut stub = Function::new([]);
+                    stub.instructions().unreachable().end();
+                    code.function(&stub);
+                }
+                function_index += 1;
+                if code.len() == defined_fn_count {
+                    module.section(&code);
+                }
+                continue;
+            }
+            Payload::DataCountSection { .. } => {
+                if let Some(data) = &data {
+                    module.section(&DataCountSection { count: data.len() });
+                    continue;
+                }
+            }
+            Payload::DataSection(_) => {
+                if let Some(data) = &data {
+                    module.section(data);
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        if let Some((id, range)) = payload.as_section() {
+            module.section(&RawSection {
+                id,
+                data: &wasm[range],
+            });
+        }
+    }
+
+    let optimized = module.finish();
+    let report = SizeReport {
+        before: Sizes::of(wasm)?,
+        after: Sizes::of(&optimized)?,
+    };
+    Ok((optimized, report))
+}
+
+/// Returns the indices of the functions that can be called, starting from the
+/// exports other than `wizer-initialize`, the start function, and functions
+/// referenced by tables and globals.
+fn live_functions(wasm: &[u8]) -> Result<HashSet<u32>> {
+    let mut imported_fn_count = 0;
+    let mut bodies: Vec<FunctionBody> = vec![];
+    let mut roots = vec![];
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    if matches!(import?.ty, TypeRef::Func(_)) {
+                        imported_fn_count += 1;
+                    }
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    if export.kind == ExternalKind::Func && export.name != INIT_EXPORT {
+                        roots.push(export.index);
+                    }
+                }
+            }
+            Payload::StartSection { func, .. } => roots.push(func),
+            Payload::GlobalSection(reader) => {
+                for global in reader {
+                    for op in global?.init_expr.get_operators_reader() {
+                        if let Operator::RefFunc { function_index } = op? {
+                            roots.push(function_index);
+                        }
+                    }
+                }
+            }
+            Payload::ElementSection(reader) => {
+                for element in reader {
+                    match element?.items {
+                        ElementItems::Functions(functions) => {
+                            for function in functions {
+                                roots.push(function?);
+                            }
+                        }
+                        ElementItems::Expressions(_, exprs) => {
+                            for expr in exprs {
+                                for op in expr?.get_operators_reader() {
+                                    if let Operator::RefFunc { function_index } = op? {
+                                        roots.push(function_index);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => bodies.push(body),
+            _ => {}
+        }
+    }
+
+    let mut live = HashSet::new();
+    while let Some(function) = roots.pop() {
+        if !live.insert(function) || function < imported_fn_count {
+            continue;
+        }
+        let body = &bodies[(function - imported_fn_count) as usize];
+        for op in body.get_operators_reader()? {
+            match op? {
+                Operator::Call { function_index }
+                | Operator::ReturnCall { function_index }
+                | Operator::RefFunc { function_index } => roots.push(function_index),
+                _ => {}
+            }
+        }
+    }
+    Ok(live)
+}
+
+/// Returns the data section with its segments merged and trimmed, or `None`
+/// to keep the data section as it is when it has segments other than active
+/// segments at constant offsets of the first memory, like Wizer emits.
+fn merged_data(wasm: &[u8]) -> Result<Option<DataSection>> {
+    let mut segments: Vec<(u32, &[u8])> = vec![];
+    for payload in Parser::new(0).parse_all(wasm) {
+        let Payload::DataSection(reader) = payload? else {
+            continue;
+        };
+        for data in reader {
+            let data = data?;
+            let DataKind::Active {
+                memory_index: 0,
+                offset_expr,
+            } = data.kind
+            else {
+                return Ok(None);
+            };
+            let mut ops = offset_expr.get_operators_reader().into_iter();
+            let (Some(Ok(Operator::I32Const { value })), Some(Ok(Operator::End)), None) =
+                (ops.next(), ops.next(), ops.next())
+            else {
+                return Ok(None);
+            };
+            segments.push((value as u32, data.data));
+        }
+    }
+    if segments.is_empty() {
+        return Ok(None);
+    }
+    segments.sort_by_key(|(offset, _)| *offset);
+    if segments
+        .windows(2)
+        .any(|pair| pair[0].0 as usize + pair[0].1.len() > pair[1].0 as usize)
+    {
+        // Later segments overwrite earlier ones, so zeros aren't redundant.
+        return Ok(None);
+    }
+
+    // Non-zero ranges of memory, merged when a new segment costs more than
+    // the zeros between them.
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for (offset, bytes) in &segments {
+        let Some(start) = bytes.iter().position(|byte| *byte != 0) else {
+            continue;
+        };
+        let end = bytes.iter().rposition(|byte| *byte != 0).unwrap() + 1;
+        let range = *offset as usize + start..*offset as usize + end;
+        match ranges.last_mut() {
+            Some(last) if range.start - last.end <= segment_overhead(&range) => {
+                last.end = range.end;
+            }
+            _ => ranges.push(range),
+        }
+    }
+
+    let mut data = DataSection::new();
+    for range in ranges {
+        let mut bytes = vec![0; range.len()];
+        for (offset, segment) in &segments {
+            let segment_range = *offset as usize..*offset as usize + segment.len();
+            let start = segment_range.start.max(range.start);
+            let end = segment_range.end.min(range.end);
+            if start < end {
+                bytes[start - range.start..end - range.start].copy_from_slice(
+                    &segment[start - segment_range.start..end - segment_range.start],
+                );
+            }
+        }
+        data.active(0, &ConstExpr::i32_const(range.start as i32), bytes);
+    }
+    Ok(Some(data))
+}
+
+/// Returns the size in bytes of the encoding of an active data segment for
+/// `range`, excluding its data.
+fn segment_overhead(range: &Range<usize>) -> usize {
+    // The flags, `i32.const` and `end` take a byte each, plus the LEB128
+    // encodings of the offset and the length.
+    let signed_leb_len = |value: i32| {
+        let bits = 33 - value.leading_zeros().min(value.leading_ones()) as usize;
+        bits.div_ceil(7)
+    };
+    let unsigned_leb_len = |value: usize| {
+        (usize::BITS as usize - value.leading_zeros() as usize)
+            .max(1)
+            .div_ceil(7)
+    };
+    3 + signed_leb_len(range.start as i32) + unsigned_leb_len(range.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_optimize() -> Result<()> {
+        let wasm = wat::parse_str(
+            r#"
+            (module $engine
+              (memory 2)
+              (export "memory" (memory 0))
+              (func $used (export "run") call $helper)
+              (func $helper)
+              (func $init (export "wizer-initialize") call $init_only)
+              (func $init_only)
+              (data (i32.const 16) "\00\00ab")
+              (data (i32.const 22) "cd\00\00")
+              (data (i32.const 70000) "ef"))
+            "#,
+        )?;
+        let (optimized, report) = optimize(&wasm)?;
+        wasmparser::validate(&optimized)?;
+        assert!(report.after.total < report.before.total);
+        assert_eq!(0, report.after.custom);
+
+        let mut data = vec![];
+        let mut stubs = 0;
+        for payload in Parser::new(0).parse_all(&optimized) {
+            match payload? {
+                Payload::ExportSection(reader) => {
+                    let names = reader
+                        .into_iter()
+                        .map(|export| Ok(export?.name))
+                        .collect::<Result<Vec<_>>>()?;
+                    assert_eq!(vec!["memory", "run"], names);
+                }
+                Payload::CodeSectionEntry(body) => {
+                    let ops = body
+                        .get_operators_reader()?
+                        .into_iter()
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if ops == [Operator::Unreachable, Operator::End] {
+                        stubs += 1;
+                    }
+                }
+                Payload::DataSection(reader) => {
+                    for segment in reader {
+                        let segment = segment?;
+                        data.push(segment.data.to_vec());
+                    }
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(2, stubs);
+        assert_eq!(vec![b"ab\0\0cd".to_vec(), b"ef".to_vec()], data);
+        Ok(())
+    }
+}
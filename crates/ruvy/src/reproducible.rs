@@ -0,0 +1,105 @@
+use std::fmt;
+
+use anyhow::Result;
+use wasmparser::{Parser, Payload};
+
+/// Size of the magic number and version starting modules.
+const MODULE_HEADER_SIZE: usize = 8;
+
+/// The result of [`crate::Compiler::verify_reproducible`].
+#[derive(Debug, Clone)]
+pub struct Verification {
+    /// SHA-256 hash of the module built first.
+    pub sha256: String,
+    /// Where the modules built differ first, or `None` if they are identical.
+    pub difference: Option<Difference>,
+}
+
+/// The first byte at which two builds of a module differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difference {
+    pub offset: usize,
+    /// Section of the first module containing the byte, e.g. `data section`
+    /// or `custom section ruvy-metadata`.
+    pub section: String,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The builds differ at offset {:#x} ({})",
+            self.offset, self.section
+        )
+    }
+}
+
+/// Compares two modules byte by byte.
+pub(crate) fn first_difference(a: &[u8], b: &[u8]) -> Result<Option<Difference>> {
+    let Some(offset) = a
+        .iter()
+        .zip(b)
+        .position(|(a, b)| a != b)
+        .or_else(|| (a.len() != b.len()).then_some(a.len().min(b.len())))
+    else {
+        return Ok(None);
+    };
+    // Sections follow each other, each starting right after the previous one
+    // with its id and size.
+    let mut section_start = MODULE_HEADER_SIZE;
+    let mut section = if offset < MODULE_HEADER_SIZE {
+        "module header".to_string()
+    } else {
+        // One module is a prefix of the other.
+        "end of the module".to_string()
+    };
+    for payload in Parser::new(0).parse_all(a) {
+        let payload = payload?;
+        let Some((id, range)) = payload.as_section() else {
+            continue;
+        };
+        if section_start <= offset && offset < range.end {
+            section = match payload {
+                Payload::CustomSection(reader) => format!("custom section {}", reader.name()),
+                _ => format!("{} section", section_name(id)),
+            };
+        }
+        section_start = range.end;
+    }
+    Ok(Some(Difference { offset, section }))
+}
+
+fn section_name(id: u8) -> &'static str {
+    match id {
+        1 => "type",
+        2 => "import",
+        3 => "function",
+        4 => "table",
+        5 => "memory",
+        6 => "global",
+        7 => "export",
+        8 => "start",
+        9 => "element",
+        10 => "code",
+        11 => "data",
+        12 => "data count",
+        13 => "tag",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_difference() -> Result<()> {
+        let a = wat::parse_str(r#"(module (memory 1) (data (i32.const 0) "abc"))"#)?;
+        let b = wat::parse_str(r#"(module (memory 1) (data (i32.const 0) "abd"))"#)?;
+        assert_eq!(None, first_difference(&a, &a)?);
+        let difference = first_difference(&a, &b)?.unwrap();
+        assert_eq!("data section", difference.section);
+        assert_eq!(a.len() - 1, difference.offset);
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use wasmtime::{Config, Engine, Linker, Module, ResourceLimiter, Store, Trap};
+use wasmtime_wasi::{p1::WasiP1Ctx, I32Exit};
+
+use crate::dynamic::ENGINE_IMPORT_NAMESPACE;
+
+/// A function of a module to run with [`execute`].
+#[derive(Debug)]
+pub struct Execution<'a> {
+    /// The module to run.
+    pub wasm: &'a [u8],
+    /// The engine to link the module against if it was compiled in dynamic
+    /// mode.
+    pub engine: Option<&'a [u8]>,
+    /// Name of the exported function to call.
+    pub function: &'a str,
+    /// Amount of fuel the run can consume, enables counting the fuel consumed.
+    pub fuel: Option<u64>,
+}
+
+/// Result of a run of [`execute`].
+#[derive(Debug)]
+pub struct Report {
+    /// The exit code passed to `proc_exit`, or 0 if the function returned.
+    pub exit_code: i32,
+    pub usage: Usage,
+}
+
+/// Resources consumed by a run.
+#[derive(Debug)]
+pub struct Usage {
+    /// Fuel consumed, if fuel consumption was enabled.
+    pub fuel: Option<u64>,
+    /// Size in bytes of the largest linear memory.
+    pub peak_memory: usize,
+    /// Time spent instantiating the module and running the function.
+    pub wall_time: Duration,
+}
+
+/// The error returned by [`execute`] when a run consumes more fuel than its
+/// budget, with the resources it consumed until it was stopped.
+#[derive(Debug)]
+pub struct OutOfFuel {
+    pub budget: u64,
+    pub usage: Usage,
+}
+
+impl fmt::Display for OutOfFuel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Exceeded the fuel budget of {}", self.budget)
+    }
+}
+
+impl std::error::Error for OutOfFuel {}
+
+struct RunState {
+    wasi: WasiP1Ctx,
+    peak_memory: PeakMemory,
+}
+
+/// Records the largest size of the linear memories without limiting them.
+#[derive(Default)]
+struct PeakMemory(usize);
+
+impl ResourceLimiter for PeakMemory {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        self.0 = self.0.max(desired);
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        _desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Runs a function of a module with the given WASI context and reports the
+/// resources it consumed.
+///
+/// Fails if the function traps, or with [`OutOfFuel`] if it consumes more fuel
+/// than its budget.
+pub async fn execute(execution: &Execution<'_>, wasi: WasiP1Ctx) -> Result<Report> {
+    let mut cfg = Config::new();
+    cfg.async_support(true);
+    cfg.consume_fuel(execution.fuel.is_some());
+    let engine = Engine::new(&cfg)?;
+    let mut linker = Linker::new(&engine);
+    wasmtime_wasi::p1::add_to_linker_async(&mut linker, |state: &mut RunState| &mut state.wasi)?;
+    let mut store = Store::new(
+        &engine,
+        RunState {
+            wasi,
+            peak_memory: PeakMemory::default(),
+        },
+    );
+    store.limiter(|state| &mut state.peak_memory);
+    if let Some(fuel) = execution.fuel {
+        store.set_fuel(fuel)?;
+    }
+
+    let ruby_engine = execution
+        .engine
+        .map(|engine_wasm| Module::new(&engine, engine_wasm))
+        .transpose()?;
+    let module = Module::new(&engine, execution.wasm)?;
+
+    let start = Instant::now();
+    if let Some(ruby_engine) = ruby_engine {
+        let instance = linker.instantiate_async(&mut store, &ruby_engine).await?;
+        linker.instance(&mut store, ENGINE_IMPORT_NAMESPACE, instance)?;
+    }
+    let instance = linker.instantiate_async(&mut store, &module).await?;
+    let function = instance.get_typed_func::<(), ()>(&mut store, execution.function)?;
+    let result = function.call_async(&mut store, ()).await;
+    let wall_time = start.elapsed();
+
+    let fuel = match execution.fuel {
+        Some(budget) => Some(budget - store.get_fuel()?),
+        None => None,
+    };
+    let usage = Usage {
+        fuel,
+        peak_memory: store.data().peak_memory.0,
+        wall_time,
+    };
+    let exit_code = match result {
+        Ok(()) => 0,
+        Err(err) => match (err.downcast_ref::<I32Exit>(), err.downcast_ref::<Trap>()) {
+            (Some(I32Exit(code)), _) => *code,
+            (_, Some(Trap::OutOfFuel)) => {
+                return Err(OutOfFuel {
+                    budget: execution.fuel.unwrap(),
+                    usage,
+                }
+                .into())
+            }
+            _ => return Err(err),
+        },
+    };
+    Ok(Report { exit_code, usage })
+}
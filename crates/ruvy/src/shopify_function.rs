@@ -0,0 +1,149 @@
+use std::fmt::Write;
+
+use anyhow::{bail, Result};
+
+use crate::{dynamic::ENGINE_IMPORT_NAMESPACE, format_size};
+use serde::Serialize;
+use wasmparser::{Parser, Payload, TypeRef};
+
+/// Name of the Wasm export Shopify Functions call.
+pub const EXPORT: &str = "run";
+
+/// Name of the Ruby method called by the export, defined by [`ENTRYPOINT`].
+pub const ENTRYPOINT_METHOD: &str = "__ruvy_shopify_function_run";
+
+/// Preloaded Ruby code calling the script's `run` method with the function's
+/// input and writing its result.
+pub const ENTRYPOINT: &str = r#"
+def __ruvy_shopify_function_run
+  output = run(JSON.parse($stdin.read))
+  $stdout.write(JSON.generate(output))
+  $stdout.flush
+end
+"#;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Limits a module must stay within to run as a Shopify Function.
+#[derive(Debug, Clone, Serialize)]
+pub struct Limits {
+    /// Maximum size of the module in bytes.
+    pub max_size: u64,
+    /// Maximum initial size of the module's linear memory in bytes.
+    pub max_memory: u64,
+    /// Modules the module is allowed to import from.
+    pub allowed_import_modules: Vec<String>,
+}
+
+impl Default for Limits {
+    /// The limits Shopify Functions currently enforce.
+    fn default() -> Self {
+        Self {
+            max_size: 256 * 1024,
+            max_memory: 10_000 * 1024,
+            // Modules built in dynamic mode import the engine.
+            allowed_import_modules: vec![
+                "wasi_snapshot_preview1".to_string(),
+                ENGINE_IMPORT_NAMESPACE.to_string(),
+            ],
+        }
+    }
+}
+
+/// Checks `wasm` is within `limits`, failing with a breakdown of the
+/// module's size and imports otherwise.
+pub fn check(wasm: &[u8], limits: &Limits) -> Result<()> {
+    let mut violations = vec![];
+    let size = wasm.len() as u64;
+    if size > limits.max_size {
+        violations.push(format!(
+            "size is {}, the limit is {}",
+            format_size(size),
+            format_size(limits.max_size)
+        ));
+    }
+
+    let mut sections = vec![];
+    let mut imports = vec![];
+    let mut memory = 0;
+    for payload in Parser::new(0).parse_all(wasm) {
+        let payload = payload?;
+        match &payload {
+            Payload::ImportSection(reader) => {
+                for import in reader.clone() {
+                    let import = import?;
+                    if let TypeRef::Memory(ty) = import.ty {
+                        memory += ty.initial * WASM_PAGE_SIZE;
+                    }
+                    imports.push(format!("{}::{}", import.module, import.name));
+                    if !limits
+                        .allowed_import_modules
+                        .iter()
+                        .any(|module| module == import.module)
+                    {
+                        violations.push(format!(
+                            "imports `{}::{}`, which is not from an allowed module ({})",
+                            import.module,
+                            import.name,
+                            limits.allowed_import_modules.join(", ")
+                        ));
+                    }
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for ty in reader.clone() {
+                    memory += ty?.initial * WASM_PAGE_SIZE;
+                }
+            }
+            _ => {}
+        }
+        if let Some((_, range)) = payload.as_section() {
+            let name = match &payload {
+                Payload::CustomSection(reader) => format!("custom `{}`", reader.name()),
+                _ => section_name(&payload).to_string(),
+            };
+            sections.push((name, range.len()));
+        }
+    }
+    if memory > limits.max_memory {
+        violations.push(format!(
+            "initial linear memory is {}, the limit is {}",
+            format_size(memory),
+            format_size(limits.max_memory)
+        ));
+    }
+
+    if violations.is_empty() {
+        return Ok(());
+    }
+    let mut report = String::from("The module exceeds the limits of Shopify Functions:\n");
+    for violation in &violations {
+        writeln!(report, "  - {violation}")?;
+    }
+    writeln!(report, "Size of the module's sections:")?;
+    for (name, size) in &sections {
+        writeln!(report, "  {name:<24} {:>12}", format_size(*size as u64))?;
+    }
+    writeln!(report, "Initial linear memory: {}", format_size(memory))?;
+    write!(report, "Imports: {}", imports.join(", "))?;
+    bail!(report)
+}
+
+fn section_name(payload: &Payload) -> &'static str {
+    match payload {
+        Payload::TypeSection(_) => "type",
+        Payload::ImportSection(_) => "import",
+        Payload::FunctionSection(_) => "function",
+        Payload::TableSection(_) => "table",
+        Payload::MemorySection(_) => "memory",
+        Payload::TagSection(_) => "tag",
+        Payload::GlobalSection(_) => "global",
+        Payload::ExportSection(_) => "export",
+        Payload::StartSection { .. } => "start",
+        Payload::ElementSection(_) => "element",
+        Payload::DataCountSection { .. } => "data count",
+        Payload::CodeSectionStart { .. } => "code",
+        Payload::DataSection(_) => "data",
+        _ => "other",
+    }
+}
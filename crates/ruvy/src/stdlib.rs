@@ -0,0 +1,71 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+
+use crate::metadata::sha256;
+
+/// The standard library of the ruby.wasm the engine is built with, in a
+/// `stdlib` directory.
+const ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/stdlib.tar.gz"));
+
+/// Unpacks the Ruby standard library embedded in ruvy into `destination`, and
+/// returns its directories in load order.
+///
+/// `RUVY_STDLIB_PATH` can point at the `lib/ruby/3.2.0` directory of a
+/// different ruby.wasm, which is used in place of the embedded one.
+pub fn unpack(destination: &Path) -> Result<Vec<PathBuf>> {
+    let stdlib = match env::var_os("RUVY_STDLIB_PATH") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            tar::Archive::new(GzDecoder::new(ARCHIVE))
+                .unpack(destination)
+                .context("Could not unpack the Ruby standard library")?;
+            destination.join("stdlib")
+        }
+    };
+    if !stdlib.is_dir() {
+        bail!(
+            "Could not find the Ruby standard library at `{}`, set RUVY_STDLIB_PATH to the `lib/ruby/3.2.0` directory of ruby.wasm",
+            stdlib.display()
+        );
+    }
+    let mut paths = vec![stdlib.clone()];
+    // Contains `rbconfig.rb`, which some libraries require.
+    let arch = stdlib.join("wasm32-wasi");
+    if arch.is_dir() {
+        paths.push(arch);
+    }
+    Ok(paths)
+}
+
+/// Identifies the standard library builds load libraries from: the canonical
+/// path of `RUVY_STDLIB_PATH` if it's set, or the hash of the embedded one.
+pub fn id() -> Result<String> {
+    match env::var_os("RUVY_STDLIB_PATH") {
+        Some(path) => {
+            let path = Path::new(&path)
+                .canonicalize()
+                .with_context(|| format!("Could not find {}", path.to_string_lossy()))?;
+            Ok(path.to_string_lossy().into_owned())
+        }
+        None => Ok(sha256(ARCHIVE)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unpack() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let paths = unpack(dir.path())?;
+        assert_eq!(dir.path().join("stdlib"), paths[0]);
+        assert!(paths.iter().all(|path| path.is_dir()));
+        Ok(())
+    }
+}
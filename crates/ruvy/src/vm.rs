@@ -0,0 +1,356 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use wasmtime::{Config, Engine, Linker, Store};
+use wasmtime_wasi::{
+    p1::WasiP1Ctx,
+    p2::pipe::{MemoryInputPipe, MemoryOutputPipe},
+    Deterministic, DirPerms, FilePerms, HostMonotonicClock, HostWallClock, I32Exit, WasiCtxBuilder,
+};
+use wasmtime_wizer::Wizer;
+
+use crate::{
+    diagnostics::{self, CompileError, Diagnostic, Severity},
+    gems::Gem,
+    heap_report::{self, HeapReport},
+    IoFormat,
+};
+
+/// Guest path of the writable directory the engine writes reports to.
+const REPORT_DIR: &str = "/ruvy/reports";
+
+/// Configures the Ruby VM before the snapshot is taken.
+#[derive(Debug, Default)]
+pub struct VmConfig<'a> {
+    pub input_path: Option<&'a Path>,
+    pub preload_paths: &'a [PathBuf],
+    pub load_paths: &'a [PathBuf],
+    pub stdlib: &'a [String],
+    /// Directories of the standard library the `stdlib` libraries are loaded
+    /// from, see [`crate::stdlib::unpack`].
+    pub stdlib_paths: &'a [PathBuf],
+    pub gems: &'a [Gem],
+    pub exports: &'a [String],
+    pub io_format: IoFormat,
+    pub heap_report: bool,
+    pub reproducible: bool,
+}
+
+/// A snapshot of the Ruby engine and what Ruby printed while it was taken.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub wasm: Vec<u8>,
+    pub stdout: Vec<u8>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub heap_report: Option<HeapReport>,
+    /// Host paths of the files Ruby loaded with `require`.
+    pub loaded_files: Vec<PathBuf>,
+}
+
+/// Initializes `ruby_engine` with `ruby_code` and takes a snapshot of it.
+///
+/// Fails with a [`CompileError`] if the engine exits, e.g. because the code
+/// raised an exception or called `exit`.
+pub async fn wizen(
+    ruby_engine: &[u8],
+    ruby_code: &str,
+    vm_config: &VmConfig<'_>,
+) -> Result<Snapshot> {
+    let stdout = MemoryOutputPipe::new(usize::MAX);
+    let stderr = MemoryOutputPipe::new(usize::MAX);
+    let mut cfg = Config::new();
+    cfg.async_support(true);
+    let engine = Engine::new(&cfg)?;
+    let report_dir = tempfile::tempdir()?;
+    let (wasi, mounts) = wasi(
+        ruby_code,
+        vm_config,
+        report_dir.path(),
+        stdout.clone(),
+        stderr.clone(),
+    )?;
+    let mut store = Store::new(&engine, wasi);
+    let result = Wizer::new()
+        .run(&mut store, ruby_engine, async |store, module| {
+            let engine = store.engine();
+            let mut linker = Linker::new(engine);
+            wasmtime_wasi::p1::add_to_linker_async(&mut linker, |cx| cx)?;
+            let instance = linker.instantiate_async(store, module).await?;
+            Ok(instance)
+        })
+        .await;
+    let stdout = stdout.contents().to_vec();
+    let stderr = mounts.to_host(&String::from_utf8_lossy(&stderr.contents()));
+    let heap_report = fs::read_to_string(report_dir.path().join("heap-report.tsv"))
+        .ok()
+        .map(|lines| heap_report::parse(&lines));
+    let loaded_features =
+        fs::read_to_string(report_dir.path().join("loaded-features.txt")).unwrap_or_default();
+    let loaded_files = loaded_files(&mounts, &loaded_features);
+    match result {
+        Ok(wasm) => Ok(Snapshot {
+            wasm,
+            stdout,
+            diagnostics: diagnostics::parse(&stderr, false),
+            heap_report,
+            loaded_files,
+        }),
+        Err(err) => match err.downcast_ref::<I32Exit>() {
+            // The engine fails the build when Ruby code exits, but `exit!`
+            // exits right away, even with a status of 0.
+            Some(I32Exit(0)) => {
+                let mut diagnostics = diagnostics::parse(&stderr, false);
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Error,
+                    location: None,
+                    message: "script called exit while the module was built".to_string(),
+                });
+                Err(CompileError {
+                    exit_code: 1,
+                    stdout,
+                    diagnostics,
+                }
+                .into())
+            }
+            Some(I32Exit(exit_code)) => Err(CompileError {
+                exit_code: *exit_code,
+                stdout,
+                diagnostics: diagnostics::parse(&stderr, true),
+            }
+            .into()),
+            None => Err(err),
+        },
+    }
+}
+
+/// Returns the host paths of the files the engine reported loading, one per
+/// line of `features`.
+///
+/// Features that aren't host files, like those built into Ruby, are skipped.
+fn loaded_files(mounts: &Mounts, features: &str) -> Vec<PathBuf> {
+    features
+        .lines()
+        .map(|feature| PathBuf::from(mounts.to_host(feature)))
+        .filter(|path| path.is_absolute() && path.is_file())
+        .collect()
+}
+
+fn wasi(
+    ruby_code: &str,
+    vm_config: &VmConfig,
+    report_dir: &Path,
+    stdout: MemoryOutputPipe,
+    stderr: MemoryOutputPipe,
+) -> Result<(WasiP1Ctx, Mounts)> {
+    let mut wasi_builder = WasiCtxBuilder::new();
+    wasi_builder
+        .stdin(MemoryInputPipe::new(ruby_code.as_bytes().to_owned()))
+        .stdout(stdout)
+        .stderr(stderr);
+    // The engine writes the heap report and the files it loaded there, so
+    // they don't mix with what Ruby prints.
+    wasi_builder
+        .preopened_dir(report_dir, REPORT_DIR, DirPerms::all(), FilePerms::all())?
+        .env("RUVY_REPORT_DIR", REPORT_DIR);
+    let mut mounts = Mounts::default();
+    if vm_config.reproducible {
+        // The snapshot contains the state of the random number generators
+        // and whatever Ruby derived from the clocks, like the random seed,
+        // the seed of `Hash#hash` and cached times.
+        let seed = Sha256::digest(ruby_code.as_bytes()).to_vec();
+        let epoch = env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|epoch| epoch.parse().ok())
+            .unwrap_or(0);
+        wasi_builder
+            .secure_random(Deterministic::new(seed.clone()))
+            .insecure_random(Deterministic::new(seed))
+            .insecure_random_seed(0)
+            .wall_clock(FixedClock(Duration::from_secs(epoch)))
+            .monotonic_clock(FixedClock(Duration::ZERO))
+            .env("RUVY_REPRODUCIBLE", "1");
+    }
+    if vm_config.heap_report {
+        wasi_builder.env("RUVY_HEAP_REPORT", "1");
+    }
+    if vm_config.io_format != IoFormat::Raw {
+        wasi_builder.env("RUVY_IO_FORMAT", vm_config.io_format.to_string());
+    }
+    if !vm_config.exports.is_empty() {
+        wasi_builder.env("RUVY_EXPORTS", vm_config.exports.join(","));
+    }
+    if let Some(input_path) = vm_config.input_path {
+        // `require_relative` resolves paths relative to the input file, so
+        // the guest needs its absolute path and access to its directory.
+        let input_path = mounts.mount_file(&mut wasi_builder, input_path, "input")?;
+        wasi_builder.env("RUVY_INPUT_PATH", input_path);
+    }
+    let mut load_paths = vec![];
+    for load_path in vm_config.load_paths {
+        load_paths.push(mounts.mount(&mut wasi_builder, load_path, "load-path")?);
+    }
+    if !vm_config.stdlib.is_empty() {
+        for stdlib_path in vm_config.stdlib_paths {
+            load_paths.push(mounts.mount(&mut wasi_builder, stdlib_path, "stdlib")?);
+        }
+        wasi_builder.env("RUVY_STDLIB", vm_config.stdlib.join(","));
+    }
+    if !vm_config.gems.is_empty() {
+        let mut features = vec![];
+        for gem in vm_config.gems {
+            for require_path in &gem.require_paths {
+                load_paths.push(mounts.mount(&mut wasi_builder, require_path, "gems")?);
+            }
+            features.extend(gem.feature.as_deref());
+        }
+        wasi_builder.env("RUVY_GEMS", features.join(","));
+    }
+    if !load_paths.is_empty() {
+        wasi_builder.env("RUVY_LOAD_PATH", path_list(&mounts, &load_paths)?);
+    }
+    if !vm_config.preload_paths.is_empty() {
+        let mut guest_preload_paths = vec![];
+        for preload_path in vm_config.preload_paths {
+            guest_preload_paths.push(if preload_path.is_dir() {
+                mounts.mount(&mut wasi_builder, preload_path, "preload")?
+            } else {
+                mounts.mount_file(&mut wasi_builder, preload_path, "preload")?
+            });
+        }
+        wasi_builder.env(
+            "RUVY_PRELOAD_PATH",
+            path_list(&mounts, &guest_preload_paths)?,
+        );
+    }
+    Ok((wasi_builder.build_p1(), mounts))
+}
+
+/// Host directories made readable to the guest.
+///
+/// Directories are mounted under `/ruvy` at a path that only depends on what
+/// they contain and the order they're mounted in, e.g. `/ruvy/preload`, so
+/// the paths Ruby records in the snapshot, like the paths of loaded files,
+/// don't depend on where the build runs.
+#[derive(Debug, Default)]
+struct Mounts {
+    /// Host directories and their guest paths.
+    dirs: Vec<(PathBuf, String)>,
+}
+
+impl Mounts {
+    /// Makes the directory at `path` readable in the guest, and returns its
+    /// guest path. `kind` names the directory under `/ruvy`, followed by a
+    /// number from the second directory of the same kind on.
+    fn mount(
+        &mut self,
+        wasi_builder: &mut WasiCtxBuilder,
+        path: &Path,
+        kind: &str,
+    ) -> Result<String> {
+        let path = path.canonicalize()?;
+        // Directories in mounted directories keep their place, so relative
+        // paths between them still work.
+        for (host, guest) in &self.dirs {
+            if let Ok(relative) = path.strip_prefix(host) {
+                return Ok(join(guest, relative));
+            }
+        }
+        let base = format!("/ruvy/{kind}");
+        let count = self
+            .dirs
+            .iter()
+            .filter(|(_, guest)| *guest == base || guest.starts_with(&format!("{base}-")))
+            .count();
+        let guest = match count {
+            0 => base,
+            count => format!("{base}-{}", count + 1),
+        };
+        preopen_read_only(wasi_builder, &path, &guest)?;
+        self.dirs.push((path, guest.clone()));
+        Ok(guest)
+    }
+
+    /// Makes the directory containing the file at `path` readable in the
+    /// guest, and returns the guest path of the file.
+    fn mount_file(
+        &mut self,
+        wasi_builder: &mut WasiCtxBuilder,
+        path: &Path,
+        kind: &str,
+    ) -> Result<String> {
+        let path = path.canonicalize()?;
+        let dir = self.mount(wasi_builder, path.parent().unwrap(), kind)?;
+        Ok(join(&dir, Path::new(path.file_name().unwrap())))
+    }
+
+    /// Replaces the guest paths in `text` with their host paths.
+    fn to_host(&self, text: &str) -> String {
+        let mut dirs: Vec<_> = self.dirs.iter().collect();
+        // `/ruvy/preload` is a prefix of `/ruvy/preload-2`.
+        dirs.sort_by_key(|(_, guest)| std::cmp::Reverse(guest.len()));
+        let mut text = text.to_string();
+        for (host, guest) in dirs {
+            text = text.replace(guest.as_str(), &host.to_string_lossy());
+        }
+        text
+    }
+}
+
+/// Joins guest paths with `:`, which the guest splits them on.
+///
+/// Mounted directories have fixed names, but directories and files inside
+/// them keep theirs, which can't contain `:`.
+fn path_list(mounts: &Mounts, paths: &[String]) -> Result<String> {
+    if let Some(path) = paths.iter().find(|path| path.contains(':')) {
+        bail!(
+            "{} can't be loaded because its path contains `:`",
+            mounts.to_host(path)
+        );
+    }
+    Ok(paths.join(":"))
+}
+
+fn join(dir: &str, relative: &Path) -> String {
+    if relative.as_os_str().is_empty() {
+        return dir.to_string();
+    }
+    format!("{dir}/{}", relative.to_string_lossy())
+}
+
+/// A clock that always returns the same time.
+struct FixedClock(Duration);
+
+impl HostWallClock for FixedClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self) -> Duration {
+        self.0
+    }
+}
+
+impl HostMonotonicClock for FixedClock {
+    fn resolution(&self) -> u64 {
+        1
+    }
+
+    fn now(&self) -> u64 {
+        self.0.as_nanos() as u64
+    }
+}
+
+/// Makes the host directory at `path` readable at `guest_path` in the guest.
+fn preopen_read_only(
+    wasi_builder: &mut WasiCtxBuilder,
+    path: &Path,
+    guest_path: &str,
+) -> Result<()> {
+    wasi_builder.preopened_dir(path, guest_path, DirPerms::READ, FilePerms::READ)?;
+    Ok(())
+}
@@ -1,9 +1,7 @@
 use anyhow::{bail, Result};
+use ruby_wasm_assets::{WASI_SDK_VERSION_MAJOR, WASI_SDK_VERSION_MINOR};
 use std::{env, fs, path::PathBuf};
 
-const WASI_SDK_VERSION_MAJOR: usize = 20;
-const WASI_SDK_VERSION_MINOR: usize = 0;
-
 fn main() -> Result<()> {
     let wasi_sdk_path = wasi_sdk_path()?;
     let wasi_sdk_path = wasi_sdk_path.to_string_lossy();
@@ -133,22 +131,11 @@ fn ruby_wasm_path() -> Result<PathBuf> {
 }
 
 fn download_ruby_wasm() -> Result<PathBuf> {
-    let mut ruby_wasm_dir: PathBuf = env::var("OUT_DIR")?.into();
-    ruby_wasm_dir.push("ruby-wasm");
-    fs::create_dir_all(&ruby_wasm_dir)?;
-    let mut archive_path = ruby_wasm_dir.clone();
-    let ruby_wasm_version = ruby_wasm_assets::ruby_wasm_base_name();
-    archive_path.push(&ruby_wasm_version);
-    archive_path.set_extension("tar.gz");
-
-    ruby_wasm_assets::download_ruby_wasm(&archive_path)?;
-    // Need to strip archive name, `usr`, and `local`.
-    ruby_wasm_assets::extract_tar(&archive_path, &ruby_wasm_dir, 3)?;
+    let ruby_wasm_dir = ruby_wasm_assets::ruby_wasm_dir()?;
     println!(
         "cargo:warning={} installed at {}",
-        ruby_wasm_version,
+        ruby_wasm_assets::ruby_wasm_base_name(),
         ruby_wasm_dir.display()
     );
-
     Ok(ruby_wasm_dir)
 }
@@ -1,4 +1,5 @@
 #include "./wrapper.h"
+#include <ruby/encoding.h>
 #include <unistd.h>
 
 // XXX https://github.com/WebAssembly/wasi-libc/commit/659ff414560721b1660a19685110e484a081c3d4
@@ -12,3 +13,23 @@ void asyncify_stop_rewind() {
 
 void asyncify_start_unwind(int x) {
 }
+
+char *ruvy_rstring_ptr(VALUE str) {
+    return RSTRING_PTR(str);
+}
+
+long ruvy_rstring_len(VALUE str) {
+    return RSTRING_LEN(str);
+}
+
+long ruvy_rarray_len(VALUE ary) {
+    return RARRAY_LEN(ary);
+}
+
+int ruvy_rb_type(VALUE obj) {
+    return rb_type(obj);
+}
+
+int ruvy_rstring_is_binary(VALUE str) {
+    return rb_enc_get_index(str) == rb_ascii8bit_encindex();
+}
@@ -1 +1,9 @@
 #include <ruby.h>
+
+// The following wrap macros and inline functions from the Ruby headers, which
+// bindgen can't generate bindings for. They're implemented in foo.c.
+char *ruvy_rstring_ptr(VALUE str);
+long ruvy_rstring_len(VALUE str);
+long ruvy_rarray_len(VALUE ary);
+int ruvy_rb_type(VALUE obj);
+int ruvy_rstring_is_binary(VALUE str);
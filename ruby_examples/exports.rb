@@ -0,0 +1,7 @@
+def run
+  puts "Running"
+end
+
+def validate
+  puts "Validating"
+end
@@ -0,0 +1,8 @@
+require "json"
+
+input = JSON.parse(STDIN.read)
+output = {
+  "greeting" => "Hello #{input["name"]}",
+  "items" => input["items"].map { |item| item * 2 },
+}
+puts JSON.generate(output)
@@ -0,0 +1,2 @@
+input = Ruvy.input
+Ruvy.output({ "greeting" => "Hello #{input["name"]}", "items" => input["items"].map { |item| item * 2 } })
@@ -0,0 +1,7 @@
+def run(input)
+  lines = input["cart"]["lines"]
+  total = lines.sum { |line| line["quantity"] * line["price"] }
+  {
+    "discounts" => total >= 100 ? [{ "value" => { "percentage" => 10 } }] : [],
+  }
+end